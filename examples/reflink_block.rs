@@ -6,16 +6,23 @@ use std::num::NonZeroU64;
 fn main() -> std::io::Result<()> {
     let args: Vec<_> = std::env::args().collect();
 
-    let [_, src_file, tgt_file, cluster_size] = &args[..] else {
-        eprintln!(
-            "Usage: {} <source_file> <target_file> <cluster_size>",
-            args[0]
-        );
-        return Ok(());
+    let (src_file, tgt_file, cluster_size) = match &args[..] {
+        [_, src_file, tgt_file] => (src_file, tgt_file, None),
+        [_, src_file, tgt_file, cluster_size] => (src_file, tgt_file, Some(cluster_size)),
+        _ => {
+            eprintln!(
+                "Usage: {} <source_file> <target_file> [cluster_size]",
+                args[0]
+            );
+            return Ok(());
+        }
     };
-    let cluster_size: NonZeroU64 = cluster_size.parse().expect("cannot parse cluster size");
 
     let from_file = File::open(src_file)?;
+    let cluster_size: NonZeroU64 = match cluster_size {
+        Some(cluster_size) => cluster_size.parse().expect("cannot parse cluster size"),
+        None => reflink_copy::clone_alignment(&from_file)?,
+    };
     let len = from_file.metadata()?.len();
     let to_file = File::create(tgt_file)?;
     to_file.set_len(len)?;
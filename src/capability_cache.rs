@@ -0,0 +1,164 @@
+use crate::error::Syscall;
+use crate::{sys, CopyOutcome, ReflinkError, ReflinkErrorReason, ReflinkOptions, ReflinkSupport};
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Remembers which pairs of devices do not support reflinking between each other.
+///
+/// Every failed reflink attempt costs a few syscalls: the target file is created, the clone is
+/// attempted and the target is removed again before copying. When copying many files between
+/// file systems without reflink support, [`ReflinkCapabilityCache::reflink_or_copy`] avoids this
+/// overhead by skipping straight to the copy once a reflink between the same source and
+/// target devices failed because it is not supported, or because the files are on different file
+/// systems.
+///
+/// The devices are identified by the device id of the source file and of the directory
+/// containing the target file. As device ids may change when file systems are remounted, the
+/// cache offers [`ReflinkCapabilityCache::invalidate`] and [`ReflinkCapabilityCache::clear`].
+///
+/// The cache can be shared between threads.
+///
+/// > Note: Currently the devices can only be identified on unix platforms and windows. On any other
+/// > platform, a reflink is attempted every time.
+///
+/// # Example
+///
+/// ```no_run
+/// use reflink_copy::ReflinkCapabilityCache;
+///
+/// fn copy_all(files: &[(&str, &str)]) -> std::io::Result<()> {
+///     let cache = ReflinkCapabilityCache::new();
+///     for (from, to) in files {
+///         cache.reflink_or_copy(from, to)?;
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct ReflinkCapabilityCache {
+    options: ReflinkOptions,
+    unsupported: Mutex<HashSet<(u64, u64)>>,
+}
+
+impl ReflinkCapabilityCache {
+    /// Creates an empty cache using the default [`ReflinkOptions`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty cache using the given [`ReflinkOptions`].
+    pub fn with_options(options: ReflinkOptions) -> Self {
+        Self {
+            options,
+            unsupported: Mutex::default(),
+        }
+    }
+
+    /// Attempts to reflink a file, unless reflinking between the devices of `from` and `to` is
+    /// known to be unsupported. If reflinking is skipped or fails, a conventional copy operation is
+    /// performed.
+    ///
+    /// The return value has the same meaning as for [`reflink_or_copy`](crate::reflink_or_copy).
+    pub fn reflink_or_copy(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> Result<Option<u64>, ReflinkError> {
+        self.reflink_or_copy_outcome(from, to)
+            .map(|outcome| outcome.bytes_written())
+    }
+
+    /// Like [`ReflinkCapabilityCache::reflink_or_copy`], but reports how the target was created.
+    ///
+    /// If the reflink was skipped because it is known to be unsupported, the returned outcome
+    /// carries no reflink error.
+    pub fn reflink_or_copy_outcome(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> Result<CopyOutcome, ReflinkError> {
+        #[cfg_attr(
+            feature = "tracing",
+            tracing_attributes::instrument(name = "reflink_or_copy_outcome", skip(cache))
+        )]
+        fn inner(
+            cache: &ReflinkCapabilityCache,
+            from: &Path,
+            to: &Path,
+        ) -> Result<CopyOutcome, ReflinkError> {
+            let devices = devices(from, to)
+                .syscall("stat")
+                .map_err(|err| err.with_paths(from, to))?;
+            let skip_reflink = devices.is_some_and(|devices| cache.is_unsupported(devices));
+
+            let outcome = cache
+                .options
+                .reflink_or_copy_inner(from, to, skip_reflink)?;
+            if let (Some(devices), Some(err)) = (devices, outcome.reflink_error()) {
+                if let ReflinkErrorReason::CrossDevice | ReflinkErrorReason::NotSupported =
+                    err.reason()
+                {
+                    cache.lock().insert(devices);
+                }
+            }
+
+            Ok(outcome)
+        }
+
+        inner(self, from.as_ref(), to.as_ref())
+    }
+
+    /// Returns [`ReflinkSupport::NotSupported`] if a previous reflink between the devices of `from`
+    /// and `to` failed because it is unsupported, and [`ReflinkSupport::Unknown`] otherwise.
+    pub fn reflink_support(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> io::Result<ReflinkSupport> {
+        match devices(from.as_ref(), to.as_ref())? {
+            Some(devices) if self.is_unsupported(devices) => Ok(ReflinkSupport::NotSupported),
+            _ => Ok(ReflinkSupport::Unknown),
+        }
+    }
+
+    /// Forgets everything known about the device `path` resides on, e.g. after it was remounted
+    /// with different options.
+    pub fn invalidate(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(device) = sys::device_id(path.as_ref())? {
+            self.lock()
+                .retain(|&(from, to)| from != device && to != device);
+        }
+        Ok(())
+    }
+
+    /// Forgets everything known about all devices.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn is_unsupported(&self, devices: (u64, u64)) -> bool {
+        self.lock().contains(&devices)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashSet<(u64, u64)>> {
+        // The set is always in a consistent state, even if another thread panicked
+        self.unsupported
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Returns the device ids of the source file and of the directory the target is created in.
+fn devices(from: &Path, to: &Path) -> io::Result<Option<(u64, u64)>> {
+    let to_dir = match to.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    match (sys::device_id(from)?, sys::device_id(to_dir)?) {
+        (Some(from), Some(to)) => Ok(Some((from, to))),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,281 @@
+use crate::error::Syscall;
+use crate::{clone_alignment, ReflinkBlockBuilder, ReflinkError, ReflinkErrorReason};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::num::NonZeroU64;
+
+/// Clones an arbitrary range of one file into another, copying the parts which cannot be cloned.
+///
+/// [`ReflinkBlockBuilder`] requires the ranges to follow the alignment rules of the platform.
+/// This builder splits the range instead: the part of the range which can be cloned is cloned with
+/// [`ReflinkBlockBuilder`], while an unaligned head and tail are copied byte by byte. If the
+/// offsets in the two files are not aligned to each other, nothing can be cloned and the whole
+/// range is copied. [`CloneRangeBuilder::plan`] tells how the range would be split without
+/// touching the files.
+///
+/// The range may end at the end of the source file, in which case the tail is cloned as well as
+/// long as the range also ends at or past the end of the destination. The destination is extended
+/// to the end of the range if it is shorter.
+///
+/// > Note: Currently cloning works only for windows and linux platforms. Ranges which need to be
+/// > cloned result in `Err` on any other platform.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// use reflink_copy::{CloneRangeBuilder, RangeAction};
+///
+/// fn splice() -> Result<(), Box<dyn std::error::Error>> {
+///     let from_file = File::open("source.bin")?;
+///     let to_file = File::options().write(true).open("destination.bin")?;
+///
+///     let segments = CloneRangeBuilder::new(&from_file, &to_file, 1_000_000)
+///         .from_offset(1234)
+///         .to_offset(1234)
+///         .clone_range()?;
+///     let cloned: u64 = segments
+///         .iter()
+///         .filter(|segment| segment.action() == RangeAction::Clone)
+///         .map(|segment| segment.length())
+///         .sum();
+///     println!("{} bytes have been cloned", cloned);
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CloneRangeBuilder<'from, 'to> {
+    from: &'from File,
+    from_offset: u64,
+    to: &'to File,
+    to_offset: u64,
+    length: u64,
+    cluster_size: Option<NonZeroU64>,
+}
+
+impl<'from, 'to> CloneRangeBuilder<'from, 'to> {
+    /// Creates a new instance of [`CloneRangeBuilder`].
+    pub fn new(from: &'from File, to: &'to File, length: u64) -> Self {
+        Self {
+            from,
+            from_offset: 0,
+            to,
+            to_offset: 0,
+            length,
+            cluster_size: None,
+        }
+    }
+
+    /// Sets the offset within the source file.
+    #[must_use]
+    pub fn from_offset(mut self, from_offset: u64) -> Self {
+        self.from_offset = from_offset;
+        self
+    }
+
+    /// Sets the offset within the destination file.
+    #[must_use]
+    pub fn to_offset(mut self, to_offset: u64) -> Self {
+        self.to_offset = to_offset;
+        self
+    }
+
+    /// Sets the cluster size the cloned ranges are aligned to. By default, it is queried with
+    /// [`clone_alignment`].
+    #[must_use]
+    pub fn cluster_size(mut self, cluster_size: NonZeroU64) -> Self {
+        self.cluster_size = Some(cluster_size);
+        self
+    }
+
+    /// Splits the range into the segments which are cloned and the ones which are copied,
+    /// ordered by their offset, as if the destination had already been extended to the end of
+    /// the range.
+    pub fn plan(&self) -> Result<Vec<RangeSegment>, ReflinkError> {
+        let cluster_size = match self.cluster_size {
+            Some(cluster_size) => cluster_size,
+            None => clone_alignment(self.from)?,
+        };
+        let from_len = self.from.metadata().syscall("fstat")?.len();
+        let to_len = self.to.metadata().syscall("fstat")?.len();
+        let to_end = self.to_end()?;
+
+        Ok(plan(
+            self.from_offset,
+            self.to_offset,
+            self.length,
+            cluster_size.get(),
+            self.from_offset.checked_add(self.length) == Some(from_len) && to_end >= to_len,
+        ))
+    }
+
+    /// Clones and copies the range, returning the segments it has been split into.
+    ///
+    /// Copying moves the cursors of both files. An empty range leaves both files untouched.
+    pub fn clone_range(self) -> Result<Vec<RangeSegment>, ReflinkError> {
+        #[cfg_attr(
+            feature = "tracing",
+            tracing_attributes::instrument(name = "clone_range")
+        )]
+        fn inner(builder: &CloneRangeBuilder<'_, '_>) -> Result<Vec<RangeSegment>, ReflinkError> {
+            if builder.length == 0 {
+                return Ok(Vec::new());
+            }
+
+            let cluster_size = match builder.cluster_size {
+                Some(cluster_size) => cluster_size,
+                None => clone_alignment(builder.from)?,
+            };
+            let to_end = builder.to_end()?;
+            if builder.to.metadata().syscall("fstat")?.len() < to_end {
+                builder.to.set_len(to_end).syscall("ftruncate")?;
+            }
+
+            let segments = CloneRangeBuilder {
+                cluster_size: Some(cluster_size),
+                ..*builder
+            }
+            .plan()?;
+            for segment in &segments {
+                match segment.action {
+                    RangeAction::Clone => {
+                        // `plan` never returns empty segments
+                        let length = NonZeroU64::new(segment.length).unwrap();
+                        ReflinkBlockBuilder::new(builder.from, builder.to, length)
+                            .from_offset(segment.from_offset)
+                            .to_offset(segment.to_offset)
+                            .cluster_size(cluster_size)
+                            .reflink_block()?;
+                    }
+                    RangeAction::Copy => copy_range(
+                        builder.from,
+                        segment.from_offset,
+                        builder.to,
+                        segment.to_offset,
+                        segment.length,
+                    )
+                    .syscall("copy")?,
+                }
+            }
+            Ok(segments)
+        }
+
+        inner(&self)
+    }
+
+    /// Returns the end of the range within the destination file.
+    fn to_end(&self) -> Result<u64, ReflinkError> {
+        self.to_offset.checked_add(self.length).ok_or_else(|| {
+            ReflinkError::invalid_input(
+                ReflinkErrorReason::InvalidRange,
+                "the range exceeds the maximum file size",
+            )
+        })
+    }
+}
+
+/// Whether a [`RangeSegment`] is cloned or copied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RangeAction {
+    /// The segment is cloned with [`ReflinkBlockBuilder`].
+    Clone,
+    /// The segment is copied byte by byte.
+    Copy,
+}
+
+/// A part of the range passed to [`CloneRangeBuilder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RangeSegment {
+    action: RangeAction,
+    from_offset: u64,
+    to_offset: u64,
+    length: u64,
+}
+
+impl RangeSegment {
+    /// Returns whether the segment is cloned or copied.
+    pub fn action(&self) -> RangeAction {
+        self.action
+    }
+
+    /// Returns the offset of the segment within the source file.
+    pub fn from_offset(&self) -> u64 {
+        self.from_offset
+    }
+
+    /// Returns the offset of the segment within the destination file.
+    pub fn to_offset(&self) -> u64 {
+        self.to_offset
+    }
+
+    /// Returns the length of the segment, as passed to [`ReflinkBlockBuilder`] for cloned
+    /// segments.
+    ///
+    /// On Windows, a cloned segment which ends at the end of the source file is rounded up to the
+    /// next cluster boundary, so it may extend past the end of the range.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+}
+
+/// Splits a range into an unaligned head which is copied, an aligned middle which is cloned, and
+/// an unaligned tail which is copied, unless it may be cloned because it ends at the end of the
+/// files.
+fn plan(
+    from_offset: u64,
+    to_offset: u64,
+    length: u64,
+    cluster_size: u64,
+    ends_at_eof: bool,
+) -> Vec<RangeSegment> {
+    let mut segments = Vec::new();
+    let mut push = |action, offset: u64, length: u64| {
+        if length > 0 {
+            segments.push(RangeSegment {
+                action,
+                from_offset: from_offset + offset,
+                to_offset: to_offset + offset,
+                length,
+            });
+        }
+    };
+
+    // Clusters can only be cloned into clusters
+    if from_offset % cluster_size != to_offset % cluster_size {
+        push(RangeAction::Copy, 0, length);
+        return segments;
+    }
+
+    let head = length.min((cluster_size - from_offset % cluster_size) % cluster_size);
+    let tail = (length - head) % cluster_size;
+    push(RangeAction::Copy, 0, head);
+    if tail > 0 && ends_at_eof {
+        let length = length - head;
+        // Windows clones whole clusters, the bytes past the end of the file are ignored, while
+        // Linux only accepts the exact length at the end of the file
+        if cfg!(windows) {
+            push(RangeAction::Clone, head, length + (cluster_size - tail));
+        } else {
+            push(RangeAction::Clone, head, length);
+        }
+    } else {
+        push(RangeAction::Clone, head, length - head - tail);
+        push(RangeAction::Copy, length - tail, tail);
+    }
+    segments
+}
+
+/// Copies `length` bytes from `from_offset` in `from` to `to_offset` in `to`.
+fn copy_range(
+    mut from: &File,
+    from_offset: u64,
+    mut to: &File,
+    to_offset: u64,
+    length: u64,
+) -> io::Result<()> {
+    from.seek(SeekFrom::Start(from_offset))?;
+    to.seek(SeekFrom::Start(to_offset))?;
+    if io::copy(&mut from.take(length), &mut to)? < length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
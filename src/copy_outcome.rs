@@ -0,0 +1,117 @@
+use crate::{FallbackStage, ReflinkError};
+
+/// Describes how [`reflink_or_copy_outcome`](crate::reflink_or_copy_outcome) materialized the
+/// target file.
+///
+/// Every variant except [`CopyOutcome::Reflinked`] and [`CopyOutcome::Symlink`] carries the error
+/// which made the reflink fail. It is `None` if the reflink was not attempted at all, e.g. because
+/// a [`ReflinkCapabilityCache`](crate::ReflinkCapabilityCache) knew it to be unsupported.
+///
+/// If a fallback ladder has been configured with
+/// [`ReflinkOptions::fallback`](crate::ReflinkOptions::fallback), the variant tells which stage
+/// succeeded, and the stages which failed before it are reported along with their errors.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CopyOutcome {
+    /// The file has been reflinked, its data is shared with the source.
+    Reflinked,
+    /// The source is a symlink, which has been recreated at the target as selected by
+    /// [`SymlinkPolicy::CopyLink`](crate::SymlinkPolicy::CopyLink).
+    Symlink,
+    /// The file has been copied with a conventional copy operation.
+    Copied {
+        /// The number of bytes copied.
+        bytes: u64,
+        /// The error which made the reflink fail.
+        reflink_error: Option<ReflinkError>,
+        /// The fallback stages which failed before, along with their errors.
+        failed_stages: Vec<(FallbackStage, ReflinkError)>,
+    },
+    /// The file has been copied with `copy_file_range`, which may have been performed by the
+    /// kernel or the file server without passing the data through user space.
+    CopyFileRange {
+        /// The number of bytes copied.
+        bytes: u64,
+        /// The error which made the reflink fail.
+        reflink_error: Option<ReflinkError>,
+        /// The fallback stages which failed before, along with their errors.
+        failed_stages: Vec<(FallbackStage, ReflinkError)>,
+    },
+    /// The file has been copied with `sendfile`.
+    Sendfile {
+        /// The number of bytes copied.
+        bytes: u64,
+        /// The error which made the reflink fail.
+        reflink_error: Option<ReflinkError>,
+        /// The fallback stages which failed before, along with their errors.
+        failed_stages: Vec<(FallbackStage, ReflinkError)>,
+    },
+    /// The file has been copied region by region, recreating the holes of the sparse source.
+    Sparse {
+        /// The length of the file, including the holes.
+        bytes: u64,
+        /// The number of bytes copied, not counting the holes.
+        data_bytes: u64,
+        /// The error which made the reflink fail.
+        reflink_error: Option<ReflinkError>,
+        /// The fallback stages which failed before, along with their errors.
+        failed_stages: Vec<(FallbackStage, ReflinkError)>,
+    },
+}
+
+impl CopyOutcome {
+    /// Returns `true` if the file has been reflinked.
+    pub fn is_reflinked(&self) -> bool {
+        matches!(self, CopyOutcome::Reflinked)
+    }
+
+    /// Returns the number of bytes written to the target, or `None` if the file has been reflinked
+    /// or is a recreated symlink.
+    ///
+    /// Like `std::fs::copy`, the holes of a sparse file are counted as well. The number of bytes
+    /// of data is reported by [`CopyOutcome::Sparse`]. This matches the return value of
+    /// [`reflink_or_copy`](crate::reflink_or_copy).
+    pub fn bytes_written(&self) -> Option<u64> {
+        match *self {
+            CopyOutcome::Reflinked | CopyOutcome::Symlink => None,
+            CopyOutcome::Copied { bytes, .. }
+            | CopyOutcome::CopyFileRange { bytes, .. }
+            | CopyOutcome::Sendfile { bytes, .. }
+            | CopyOutcome::Sparse { bytes, .. } => Some(bytes),
+        }
+    }
+
+    /// Returns the error which made the reflink fail, if the reflink was attempted.
+    pub fn reflink_error(&self) -> Option<&ReflinkError> {
+        match self {
+            CopyOutcome::Reflinked | CopyOutcome::Symlink => None,
+            CopyOutcome::Copied { reflink_error, .. }
+            | CopyOutcome::CopyFileRange { reflink_error, .. }
+            | CopyOutcome::Sendfile { reflink_error, .. }
+            | CopyOutcome::Sparse { reflink_error, .. } => reflink_error.as_ref(),
+        }
+    }
+
+    /// Returns the fallback stages which failed before the file could be copied, along with their
+    /// errors.
+    pub fn failed_stages(&self) -> &[(FallbackStage, ReflinkError)] {
+        match self {
+            CopyOutcome::Reflinked | CopyOutcome::Symlink => &[],
+            CopyOutcome::Copied { failed_stages, .. }
+            | CopyOutcome::CopyFileRange { failed_stages, .. }
+            | CopyOutcome::Sendfile { failed_stages, .. }
+            | CopyOutcome::Sparse { failed_stages, .. } => failed_stages,
+        }
+    }
+
+    /// Consumes the outcome, returning the error which made the reflink fail.
+    pub fn into_reflink_error(self) -> Option<ReflinkError> {
+        match self {
+            CopyOutcome::Reflinked | CopyOutcome::Symlink => None,
+            CopyOutcome::Copied { reflink_error, .. }
+            | CopyOutcome::CopyFileRange { reflink_error, .. }
+            | CopyOutcome::Sendfile { reflink_error, .. }
+            | CopyOutcome::Sparse { reflink_error, .. } => reflink_error,
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use crate::error::Syscall;
+use crate::{sys, ReflinkError, ReflinkErrorReason};
+use std::fs::File;
+use std::io;
+use std::num::NonZeroU64;
+use std::path::Path;
+
+/// The length deduplicated per call by [`dedupe_files`]. It is a multiple of every common block
+/// size, and the largest length btrfs deduplicates in a single call.
+const DEDUPE_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Deduplicates two existing files with identical content, so they share their extents.
+///
+/// The files must have the same size. They are compared and deduplicated chunk by chunk with
+/// [`DedupeRangeBuilder`], so the content of neither file is ever changed: chunks whose content
+/// differs are left alone, while identical chunks are still shared. The returned value is the
+/// number of bytes which now share their extents, which equals the size of the files if they are
+/// identical.
+///
+/// `a` is only read, `b` is opened for writing if possible, as deduplicating into a file which is
+/// not open for writing requires being its owner.
+///
+/// > Note: Currently the function works only for linux and android platforms. It returns `Err` for
+/// > any other platform.
+///
+/// ```no_run
+/// match reflink_copy::dedupe_files("artifact.bin", "duplicate.bin") {
+///     Ok(shared) => println!("{} bytes are shared", shared),
+///     Err(e) => println!("error while deduplicating: {:?}", e),
+/// }
+/// ```
+///
+/// # Linux specific remarks
+///
+/// The files are deduplicated in chunks of 16 MiB, the largest length btrfs accepts per call, and
+/// a chunk is resumed where the kernel stopped if it deduplicated less. The last chunk ends at the
+/// end of the files, even if their size is not a multiple of the block size. Kernels which refuse
+/// to deduplicate such an unaligned tail leave it unshared.
+pub fn dedupe_files(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<u64, ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "dedupe_files")
+    )]
+    fn inner(a: &Path, b: &Path) -> Result<u64, ReflinkError> {
+        let src = File::open(a).syscall("open")?;
+        let dest = match File::options().write(true).open(b) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => File::open(b),
+            dest => dest,
+        }
+        .syscall("open")?;
+
+        let len = src.metadata().syscall("fstat")?.len();
+        if dest.metadata().syscall("fstat")?.len() != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the files differ in size",
+            ))
+            .syscall("fstat");
+        }
+
+        let mut offset = 0;
+        let mut shared = 0;
+        while let Some(length) = NonZeroU64::new((len - offset).min(DEDUPE_CHUNK_SIZE)) {
+            let result = DedupeRangeBuilder::new(&src, length)
+                .from_offset(offset)
+                .destination(&dest, offset)
+                .dedupe()?
+                .pop()
+                .unwrap();
+            let bytes_deduped = result.bytes_deduped();
+
+            match result.into_result() {
+                // Resume where the kernel stopped if it deduplicated less than requested
+                Ok(DedupeStatus::Same) if bytes_deduped > 0 => {
+                    shared += bytes_deduped;
+                    offset += bytes_deduped;
+                }
+                Ok(_) => offset += length.get(),
+                // Older kernels refuse to deduplicate a tail which is not aligned to the block
+                // size
+                Err(err)
+                    if err.reason() == ReflinkErrorReason::Misaligned
+                        && offset + length.get() == len =>
+                {
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(shared)
+    }
+
+    inner(a.as_ref(), b.as_ref()).map_err(|err| err.with_paths(a.as_ref(), b.as_ref()))
+}
+
+/// Deduplicates a range of one file into ranges of other files.
+///
+/// Unlike [`ReflinkBlockBuilder`](crate::ReflinkBlockBuilder), the kernel first compares the
+/// content of the ranges and only shares the extents of a destination whose content is identical
+/// to the source. A destination whose content differs is left untouched, so deduplication never
+/// changes the data of any file, even if the files are modified concurrently.
+///
+/// Each destination is reported with its own [`DedupeRangeResult`], in the order the destinations
+/// were added. The operation itself only fails if the source range is invalid or cannot be read.
+///
+/// > Note: Currently the function works only for linux and android platforms. It returns `Err`
+/// > for any other platform.
+///
+/// # Linux specific restrictions and remarks
+///
+/// - The ranges must begin at a block boundary, and end at one as well unless they end at the end
+///   of the source file. Misaligned destinations are reported as
+///   [`ReflinkErrorReason::Misaligned`](crate::ReflinkErrorReason::Misaligned).
+/// - The destinations must be open for writing, unless the caller owns them or has
+///   `CAP_SYS_ADMIN`.
+/// - Some file systems, e.g. btrfs, deduplicate at most 16 MiB per call and destination. Check
+///   [`DedupeRangeResult::bytes_deduped`] and continue after the deduplicated bytes if needed.
+/// - The kernel accepts a limited number of destinations per call, so large sets of destinations
+///   are split into several calls.
+///
+/// More information about deduplication on Linux can be found by the
+/// [link](https://www.man7.org/linux/man-pages/man2/ioctl_fideduperange.2.html).
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::fs::File;
+/// use std::num::NonZeroU64;
+///
+/// use reflink_copy::{DedupeRangeBuilder, DedupeStatus};
+///
+/// fn dedupe() -> Result<(), Box<dyn std::error::Error>> {
+///     let original = File::open("original.bin")?;
+///     let copy1 = File::options().write(true).open("copy1.bin")?;
+///     let copy2 = File::options().write(true).open("copy2.bin")?;
+///     let len = NonZeroU64::new(original.metadata()?.len()).unwrap();
+///
+///     let results = DedupeRangeBuilder::new(&original, len)
+///         .destination(&copy1, 0)
+///         .destination(&copy2, 0)
+///         .dedupe()?;
+///     for result in results {
+///         match result.into_result() {
+///             Ok(DedupeStatus::Same) => println!("deduplicated"),
+///             Ok(status) => println!("not deduplicated: {:?}", status),
+///             Err(e) => println!("error while deduplicating: {:?}", e),
+///         }
+///     }
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug)]
+pub struct DedupeRangeBuilder<'from, 'to> {
+    from: &'from File,
+    from_offset: u64,
+    src_length: u64,
+    destinations: Vec<(&'to File, u64)>,
+}
+
+impl<'from, 'to> DedupeRangeBuilder<'from, 'to> {
+    /// Creates a new instance of [`DedupeRangeBuilder`] without any destination.
+    pub fn new(from: &'from File, src_length: NonZeroU64) -> Self {
+        Self {
+            from,
+            from_offset: 0,
+            src_length: src_length.get(),
+            destinations: Vec::new(),
+        }
+    }
+
+    /// Sets the offset within the source file.
+    #[must_use]
+    pub fn from_offset(mut self, from_offset: u64) -> Self {
+        self.from_offset = from_offset;
+        self
+    }
+
+    /// Adds a destination file and the offset of the range within it.
+    #[must_use]
+    pub fn destination(mut self, to: &'to File, to_offset: u64) -> Self {
+        self.destinations.push((to, to_offset));
+        self
+    }
+
+    /// Performs the deduplication, returning one result per destination.
+    pub fn dedupe(self) -> Result<Vec<DedupeRangeResult>, ReflinkError> {
+        sys::dedupe_range(
+            self.from,
+            self.from_offset,
+            self.src_length,
+            &self.destinations,
+        )
+    }
+}
+
+/// Tells whether the content of a destination matched the source, see [`DedupeRangeBuilder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DedupeStatus {
+    /// The content was identical, the bytes reported by [`DedupeRangeResult::bytes_deduped`] now
+    /// share their extents with the source.
+    Same,
+    /// The content differs, the destination has been left untouched.
+    Differs,
+}
+
+/// The result of deduplicating a range into a single destination with [`DedupeRangeBuilder`].
+#[derive(Debug)]
+pub struct DedupeRangeResult {
+    pub(crate) result: Result<DedupeStatus, ReflinkError>,
+    pub(crate) bytes_deduped: u64,
+}
+
+impl DedupeRangeResult {
+    /// Returns whether the content of the destination matched the source, or `None` if the
+    /// destination could not be compared.
+    pub fn status(&self) -> Option<DedupeStatus> {
+        self.result.as_ref().ok().copied()
+    }
+
+    /// Returns the number of bytes which now share their extents with the source.
+    pub fn bytes_deduped(&self) -> u64 {
+        self.bytes_deduped
+    }
+
+    /// Returns the error if the destination could not be compared or deduplicated.
+    pub fn error(&self) -> Option<&ReflinkError> {
+        self.result.as_ref().err()
+    }
+
+    /// Consumes the result and returns the status of the destination.
+    pub fn into_result(self) -> Result<DedupeStatus, ReflinkError> {
+        self.result
+    }
+}
@@ -0,0 +1,209 @@
+use crate::sys;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The classified reason of a [`ReflinkError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ReflinkErrorReason {
+    /// The source and the destination are on different file systems or volumes.
+    CrossDevice,
+    /// The file system does not support reflinks.
+    NotSupported,
+    /// The source path is not an existing regular file.
+    SourceNotRegularFile,
+    /// The block to reflink is not aligned to the cluster size of the file system.
+    Misaligned,
+    /// The block to reflink is invalid, e.g. it overlaps with the destination block in the same
+    /// file. See [`ReflinkBlockBuilder::validate`].
+    ///
+    /// [`ReflinkBlockBuilder::validate`]: crate::ReflinkBlockBuilder::validate
+    InvalidRange,
+    /// The destination already exists.
+    DestinationExists,
+    /// The permission to read the source or to create the destination was denied.
+    PermissionDenied,
+    /// The source or the directory of the destination does not exist.
+    NotFound,
+    /// The owner of the source could not be given to the destination, because the process lacks
+    /// the privileges to do so. See [`ReflinkOptions::preserve_ownership`].
+    ///
+    /// [`ReflinkOptions::preserve_ownership`]: crate::ReflinkOptions::preserve_ownership
+    OwnershipNotPermitted,
+    /// Any other reason.
+    Other,
+}
+
+/// The error type for reflink operations.
+///
+/// In addition to the underlying [`io::Error`], it carries the name of the failing system call,
+/// the paths involved if any, and a [`ReflinkErrorReason`] to branch on.
+///
+/// `ReflinkError` can be converted into an [`io::Error`], so it can be propagated with `?` from
+/// functions returning [`io::Result`]. The converted error keeps the `ReflinkError` as its inner
+/// error, which can be recovered with [`io::Error::get_ref`] and `downcast_ref`.
+///
+/// # Example
+///
+/// ```rust
+/// use reflink_copy::ReflinkErrorReason;
+///
+/// match reflink_copy::reflink("src.txt", "dest.txt") {
+///     Ok(()) => println!("file has been reflinked"),
+///     Err(e) if e.reason() == ReflinkErrorReason::NotSupported => println!("reflink unsupported"),
+///     Err(e) => println!("error while reflinking: {}", e),
+/// }
+/// ```
+#[derive(Debug)]
+pub struct ReflinkError {
+    reason: ReflinkErrorReason,
+    syscall: &'static str,
+    from: Option<PathBuf>,
+    to: Option<PathBuf>,
+    source: io::Error,
+}
+
+impl ReflinkError {
+    /// Creates an error for a failed `syscall`, classifying `source`.
+    ///
+    /// Errors of the OS telling that an operation is not supported are only classified as
+    /// [`ReflinkErrorReason::NotSupported`] by [`ReflinkError::with_clone_reason`], as they do not
+    /// mean that reflinks are unsupported if they are returned by any other system call.
+    pub(crate) fn new(syscall: &'static str, source: io::Error) -> Self {
+        let reason = match source.kind() {
+            io::ErrorKind::Unsupported if source.raw_os_error().is_none() => {
+                ReflinkErrorReason::NotSupported
+            }
+            io::ErrorKind::AlreadyExists => ReflinkErrorReason::DestinationExists,
+            io::ErrorKind::PermissionDenied => ReflinkErrorReason::PermissionDenied,
+            io::ErrorKind::NotFound => ReflinkErrorReason::NotFound,
+            _ => ReflinkErrorReason::Other,
+        };
+
+        Self {
+            reason,
+            syscall,
+            from: None,
+            to: None,
+            source,
+        }
+    }
+
+    /// Creates an error for invalid arguments, which is detected before any system call.
+    pub(crate) fn invalid_input(reason: ReflinkErrorReason, message: &'static str) -> Self {
+        Self {
+            reason,
+            syscall: "",
+            from: None,
+            to: None,
+            source: io::Error::new(io::ErrorKind::InvalidInput, message),
+        }
+    }
+
+    /// Classifies the error of a system call cloning data, which also tells whether the file
+    /// system supports reflinks at all.
+    pub(crate) fn with_clone_reason(mut self) -> Self {
+        if let Some(reason) = sys::error_reason(&self.source) {
+            self.reason = reason;
+        }
+        self
+    }
+
+    /// Overrides the classified reason.
+    pub(crate) fn with_reason(mut self, reason: ReflinkErrorReason) -> Self {
+        self.reason = reason;
+        self
+    }
+
+    /// Attaches the paths of the operation, unless already set.
+    pub(crate) fn with_paths(mut self, from: &Path, to: &Path) -> Self {
+        self.from.get_or_insert_with(|| from.into());
+        self.to.get_or_insert_with(|| to.into());
+        self
+    }
+
+    /// Returns the classified reason of the error.
+    pub fn reason(&self) -> ReflinkErrorReason {
+        self.reason
+    }
+
+    /// Returns the name of the system call which failed, e.g. `ioctl_ficlone`.
+    ///
+    /// The name is empty if the arguments have been rejected before any system call.
+    pub fn syscall(&self) -> &'static str {
+        self.syscall
+    }
+
+    /// Returns the source path of the operation, if any.
+    pub fn from(&self) -> Option<&Path> {
+        self.from.as_deref()
+    }
+
+    /// Returns the destination path of the operation, if any.
+    pub fn to(&self) -> Option<&Path> {
+        self.to.as_deref()
+    }
+
+    /// Returns the corresponding [`io::ErrorKind`] for this error.
+    ///
+    /// This is the kind of the underlying [`io::Error`], except for
+    /// [`ReflinkErrorReason::SourceNotRegularFile`], which is reported as
+    /// [`io::ErrorKind::InvalidInput`].
+    pub fn kind(&self) -> io::ErrorKind {
+        match self.reason {
+            ReflinkErrorReason::SourceNotRegularFile => io::ErrorKind::InvalidInput,
+            _ => self.source.kind(),
+        }
+    }
+
+    /// Returns the underlying [`io::Error`].
+    pub fn io_error(&self) -> &io::Error {
+        &self.source
+    }
+
+    /// Consumes the error, returning the underlying [`io::Error`].
+    pub fn into_io_error(self) -> io::Error {
+        self.source
+    }
+}
+
+impl fmt::Display for ReflinkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.reason == ReflinkErrorReason::SourceNotRegularFile {
+            f.write_str("the source path is not an existing regular file: ")?;
+        }
+        if !self.syscall.is_empty() {
+            write!(f, "{} failed: ", self.syscall)?;
+        }
+        write!(f, "{}", self.source)?;
+        match (&self.from, &self.to) {
+            (Some(from), Some(to)) => write!(f, " ({} -> {})", from.display(), to.display()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error for ReflinkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl From<ReflinkError> for io::Error {
+    fn from(err: ReflinkError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Extension trait to turn a failed system call into a [`ReflinkError`].
+pub(crate) trait Syscall<T> {
+    fn syscall(self, syscall: &'static str) -> Result<T, ReflinkError>;
+}
+
+impl<T, E: Into<io::Error>> Syscall<T> for Result<T, E> {
+    fn syscall(self, syscall: &'static str) -> Result<T, ReflinkError> {
+        self.map_err(|err| ReflinkError::new(syscall, err.into()))
+    }
+}
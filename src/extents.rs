@@ -0,0 +1,262 @@
+use crate::error::Syscall;
+use crate::{sys, ReflinkError, ReflinkErrorReason};
+use std::fs::File;
+use std::path::Path;
+
+/// Returns the extents of a file, i.e. the mapping of its content to the storage device, ordered
+/// by their offset in the file.
+///
+/// Holes of sparse files are not part of any extent. The [`FileExtent::is_shared`] flag tells
+/// whether an extent is shared with another file, e.g. because the file has been reflinked or
+/// deduplicated, so the function can be used to audit whether [`reflink_or_copy`] had to fall back
+/// to a copy. The boundaries of the extents are also good candidates to split a file into the
+/// ranges passed to [`ReflinkBlockBuilder`].
+///
+/// The mapping is a snapshot: it may change as soon as the function returns if the file is being
+/// written to, or if the file system reorganizes its data.
+///
+/// > Note: Currently the function works only for linux and android platforms. It returns `Err` for
+/// > any other platform.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// fn audit() -> Result<(), Box<dyn std::error::Error>> {
+///     let file = File::open("dest.txt")?;
+///     let extents = reflink_copy::file_extents(&file)?;
+///     let shared: u64 = extents
+///         .iter()
+///         .filter(|extent| extent.is_shared())
+///         .map(|extent| extent.length())
+///         .sum();
+///     println!("{} bytes are shared with other files", shared);
+///     Ok(())
+/// }
+/// ```
+///
+/// # Linux specific remarks
+///
+/// Uses the `FS_IOC_FIEMAP` ioctl with `FIEMAP_FLAG_SYNC`, so data which has not been written back
+/// yet is flushed first and mapped as well. Most file systems support it, including ext4, btrfs
+/// and XFS, but not tmpfs.
+///
+/// More information about the extent mapping on Linux can be found by the
+/// [link](https://www.kernel.org/doc/html/latest/filesystems/fiemap.html).
+///
+/// [`reflink_or_copy`]: crate::reflink_or_copy
+/// [`ReflinkBlockBuilder`]: crate::ReflinkBlockBuilder
+pub fn file_extents(file: &File) -> Result<Vec<FileExtent>, ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "file_extents")
+    )]
+    fn inner(file: &File) -> Result<Vec<FileExtent>, ReflinkError> {
+        sys::file_extents(file)
+    }
+
+    inner(file)
+}
+
+/// Compares the physical extents of two files to tell how much of the data of `a` is stored in
+/// the same place as data of `b`, e.g. because one of them has been reflinked from the other.
+///
+/// The extents of both files are retrieved with [`file_extents`], and every byte of `a` which is
+/// mapped to an extent is accounted for in exactly one category of the returned [`SharedBytes`].
+/// Holes and the data stored inline with the metadata are not accounted for, as they do not take
+/// up data blocks of their own. Both files must be on the same device, as the physical offsets of
+/// different file systems cannot be compared, otherwise the function fails with
+/// [`ReflinkErrorReason::CrossDevice`].
+///
+/// > Note: Currently the function works only for linux and android platforms. It returns `Err` for
+/// > any other platform.
+///
+/// ```no_run
+/// match reflink_copy::shared_bytes("src.txt", "dest.txt") {
+///     Ok(bytes) => println!("{} bytes of src.txt are shared with dest.txt", bytes.shared()),
+///     Err(e) => println!("error while comparing the extents: {:?}", e),
+/// }
+/// ```
+pub fn shared_bytes(a: impl AsRef<Path>, b: impl AsRef<Path>) -> Result<SharedBytes, ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "shared_bytes")
+    )]
+    fn inner(a: &Path, b: &Path) -> Result<SharedBytes, ReflinkError> {
+        if let (Some(a_device), Some(b_device)) = (
+            sys::device_id(a).syscall("stat")?,
+            sys::device_id(b).syscall("stat")?,
+        ) {
+            if a_device != b_device {
+                return Err(ReflinkError::invalid_input(
+                    ReflinkErrorReason::CrossDevice,
+                    "the files are on different devices",
+                ));
+            }
+        }
+
+        let a_extents = file_extents(&File::open(a).syscall("open")?)?;
+        let b_extents = file_extents(&File::open(b).syscall("open")?)?;
+
+        // The physical ranges of `b`, sorted and merged
+        let mut b_ranges: Vec<(u64, u64)> = b_extents
+            .iter()
+            .filter(|extent| extent.has_physical_location())
+            .map(|extent| (extent.physical, extent.physical + extent.length))
+            .collect();
+        b_ranges.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(b_ranges.len());
+        for (start, end) in b_ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let mut bytes = SharedBytes::default();
+        for extent in a_extents.iter().filter(|extent| !extent.is_inline()) {
+            let overlap = if extent.has_physical_location() {
+                let (start, end) = (extent.physical, extent.physical + extent.length);
+                let first = merged.partition_point(|range| range.1 <= start);
+                merged[first..]
+                    .iter()
+                    .take_while(|range| range.0 < end)
+                    .map(|range| range.1.min(end) - range.0.max(start))
+                    .sum()
+            } else {
+                0
+            };
+
+            bytes.shared += overlap;
+            if overlap == 0 {
+                bytes.distinct += extent.length;
+            } else {
+                bytes.partially_shared += extent.length - overlap;
+            }
+        }
+        Ok(bytes)
+    }
+
+    inner(a.as_ref(), b.as_ref()).map_err(|err| err.with_paths(a.as_ref(), b.as_ref()))
+}
+
+/// The amount of data of a file which is physically shared with another file, see
+/// [`shared_bytes`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SharedBytes {
+    shared: u64,
+    partially_shared: u64,
+    distinct: u64,
+}
+
+impl SharedBytes {
+    /// Returns the number of bytes stored in the same place as data of the other file. This is
+    /// the amount of storage saved by sharing.
+    pub fn shared(&self) -> u64 {
+        self.shared
+    }
+
+    /// Returns the number of bytes which are not shared themselves, but are part of an extent
+    /// which is partly shared with the other file, e.g. because a reflinked file has been
+    /// modified in the middle of an extent afterwards.
+    pub fn partially_shared(&self) -> u64 {
+        self.partially_shared
+    }
+
+    /// Returns the number of bytes in extents which are not shared with the other file at all.
+    pub fn distinct(&self) -> u64 {
+        self.distinct
+    }
+
+    /// Returns the total number of bytes accounted for, i.e. the sum of all the categories.
+    pub fn total(&self) -> u64 {
+        self.shared + self.partially_shared + self.distinct
+    }
+}
+
+// Flags of `struct fiemap_extent`, see the link above
+const FIEMAP_EXTENT_LAST: u32 = 0x0000_0001;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x0000_0002;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x0000_0004;
+const FIEMAP_EXTENT_ENCODED: u32 = 0x0000_0008;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x0000_0200;
+const FIEMAP_EXTENT_UNWRITTEN: u32 = 0x0000_0800;
+const FIEMAP_EXTENT_SHARED: u32 = 0x0000_2000;
+
+/// A contiguous range of a file stored contiguously on the storage device, see [`file_extents`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FileExtent {
+    pub(crate) logical: u64,
+    pub(crate) physical: u64,
+    pub(crate) length: u64,
+    pub(crate) flags: u32,
+}
+
+impl FileExtent {
+    /// Returns the offset of the extent within the file.
+    pub fn logical(&self) -> u64 {
+        self.logical
+    }
+
+    /// Returns the offset of the extent on the storage device. It is meaningless if
+    /// [`FileExtent::is_unknown`] is set, and the data is not stored byte by byte from there on if
+    /// [`FileExtent::is_encoded`] is set.
+    pub fn physical(&self) -> u64 {
+        self.physical
+    }
+
+    /// Returns the length of the extent in bytes.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns the raw flags of the extent, i.e. the `FIEMAP_EXTENT_*` flags on Linux.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    /// Returns `true` if the extent is shared with other files, or with other ranges of the same
+    /// file.
+    pub fn is_shared(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_SHARED != 0
+    }
+
+    /// Returns `true` if the extent is allocated but has not been written to, so it reads as
+    /// zeroes.
+    pub fn is_unwritten(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNWRITTEN != 0
+    }
+
+    /// Returns `true` if the data is stored inline with the metadata of the file, rather than in a
+    /// data block of its own.
+    pub fn is_inline(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_DATA_INLINE != 0
+    }
+
+    /// Returns `true` if the location of the extent on the storage device is not known yet.
+    pub fn is_unknown(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_UNKNOWN != 0
+    }
+
+    /// Returns `true` if the extent has not been allocated yet because its data is still being
+    /// buffered.
+    pub fn is_delalloc(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_DELALLOC != 0
+    }
+
+    /// Returns `true` if the data is stored in an encoded form, e.g. compressed, so it cannot be
+    /// read directly from the storage device.
+    pub fn is_encoded(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_ENCODED != 0
+    }
+
+    /// Returns `true` if this is the last extent of the file.
+    pub fn is_last(&self) -> bool {
+        self.flags & FIEMAP_EXTENT_LAST != 0
+    }
+
+    /// Returns `true` if the physical offset of the extent identifies its data on the storage
+    /// device, so it can be compared with the ones of other extents.
+    fn has_physical_location(&self) -> bool {
+        !self.is_unknown() && !self.is_inline() && !self.is_delalloc()
+    }
+}
@@ -0,0 +1,205 @@
+use crate::error::Syscall;
+use crate::preserve::Preserve;
+use crate::sys::AutoRemovedFile;
+use crate::{sys, CopyOutcome, ReflinkError, ReflinkErrorReason};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// A way to copy a file used by [`ReflinkOptions::reflink_or_copy`] when reflinking fails.
+///
+/// See [`ReflinkOptions::fallback`] for how the stages are combined.
+///
+/// [`ReflinkOptions::reflink_or_copy`]: crate::ReflinkOptions::reflink_or_copy
+/// [`ReflinkOptions::fallback`]: crate::ReflinkOptions::fallback
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum FallbackStage {
+    /// Copies the file with `copy_file_range`, which lets the kernel copy the data without passing
+    /// it through user space. Depending on the file system, this results in a server-side copy
+    /// (NFS, SMB), an in-kernel copy (ext4), or even a partial reflink (XFS).
+    ///
+    /// > Note: Only available on Linux and Android.
+    CopyFileRange,
+    /// Copies the file with `sendfile`, which copies the data within the kernel.
+    ///
+    /// > Note: Only available on Linux and Android.
+    Sendfile,
+    /// Copies the file with plain `read` and `write` calls. Available on all platforms.
+    ReadWrite,
+    /// Copies only the data regions of the file, found with `SEEK_DATA` and `SEEK_HOLE`, and
+    /// recreates the holes in between. This keeps sparse files such as VM images sparse on file
+    /// systems without reflink support.
+    ///
+    /// > Note: Only available on Linux and Android.
+    Sparse,
+}
+
+/// The stages used instead of `std::fs::copy` if the target needs to be modified after copying.
+pub(crate) const DEFAULT_STAGES: &[FallbackStage] =
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        &[
+            FallbackStage::CopyFileRange,
+            FallbackStage::Sendfile,
+            FallbackStage::ReadWrite,
+        ]
+    } else {
+        &[FallbackStage::ReadWrite]
+    };
+
+/// Copies `from` to `to` by trying each of `stages` in order until one succeeds.
+///
+/// `to` is created and must not exist. It is truncated before each stage, so a stage failing
+/// halfway does not leave partial data behind for the next one, and it is removed if all stages
+/// fail.
+pub(crate) fn copy(
+    stages: &[FallbackStage],
+    from: &Path,
+    to: &Path,
+    reflink_error: Option<ReflinkError>,
+    preserve: &Preserve,
+) -> Result<CopyOutcome, ReflinkError> {
+    copy_with(stages, from, to, None, reflink_error, preserve)
+}
+
+/// Like [`copy`], but copies into `dest`, which has already been created for `to`, e.g. as an
+/// unnamed temporary file. `dest` is left for the caller to discard if all stages fail.
+#[allow(dead_code)]
+pub(crate) fn copy_to_file(
+    stages: &[FallbackStage],
+    from: &Path,
+    to: &Path,
+    dest: &mut File,
+    reflink_error: Option<ReflinkError>,
+    preserve: &Preserve,
+) -> Result<CopyOutcome, ReflinkError> {
+    copy_with(stages, from, to, Some(dest), reflink_error, preserve)
+}
+
+/// Copies into `dest`, or into `to` created with `create_new` if `dest` is `None`, which is
+/// removed again if copying fails.
+fn copy_with(
+    stages: &[FallbackStage],
+    from: &Path,
+    to: &Path,
+    dest: Option<&mut File>,
+    reflink_error: Option<ReflinkError>,
+    preserve: &Preserve,
+) -> Result<CopyOutcome, ReflinkError> {
+    if stages.is_empty() {
+        return Err(reflink_error.unwrap_or_else(|| {
+            ReflinkError::new("copy", io::ErrorKind::Unsupported.into())
+                .with_reason(ReflinkErrorReason::NotSupported)
+        }));
+    }
+
+    let src = sys::open_source(from, preserve)?;
+    let metadata = src.metadata().syscall("fstat")?;
+    if !metadata.is_file() {
+        return Err(ReflinkError::new(
+            "open",
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the source path is neither a regular file nor a symlink to a regular file",
+            ),
+        )
+        .with_reason(ReflinkErrorReason::SourceNotRegularFile));
+    }
+
+    let mut created = None;
+    let dest = match dest {
+        Some(dest) => dest,
+        None => created
+            .insert(AutoRemovedFile::create_new(to).syscall("open")?)
+            .as_inner_file_mut(),
+    };
+
+    let mut failed_stages = Vec::new();
+    for &stage in stages {
+        let result = rewind(&src, dest).and_then(|()| match stage {
+            FallbackStage::CopyFileRange => sys::copy_file_range(&src, dest),
+            FallbackStage::Sendfile => sys::sendfile(&src, dest),
+            FallbackStage::ReadWrite => read_write(&src, dest),
+            FallbackStage::Sparse => sys::copy_sparse(&src, dest),
+        });
+
+        let bytes = match result {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!(?err, ?stage, "Fallback stage failed");
+
+                failed_stages.push((stage, err.with_paths(from, to)));
+                continue;
+            }
+        };
+
+        // Like `fs::copy`, the length of a sparse file counts its holes as well
+        let length = preserve
+            .finish(&src, &metadata, dest)
+            .and_then(|()| match stage {
+                FallbackStage::Sparse => dest.metadata().map(|m| m.len()).syscall("fstat"),
+                _ => Ok(bytes),
+            })?;
+        if let Some(created) = created {
+            created.persist();
+        }
+
+        return Ok(match stage {
+            FallbackStage::CopyFileRange => CopyOutcome::CopyFileRange {
+                bytes,
+                reflink_error,
+                failed_stages,
+            },
+            FallbackStage::Sendfile => CopyOutcome::Sendfile {
+                bytes,
+                reflink_error,
+                failed_stages,
+            },
+            FallbackStage::ReadWrite => CopyOutcome::Copied {
+                bytes,
+                reflink_error,
+                failed_stages,
+            },
+            FallbackStage::Sparse => CopyOutcome::Sparse {
+                bytes: length,
+                data_bytes: bytes,
+                reflink_error,
+                failed_stages,
+            },
+        });
+    }
+
+    // All stages failed, report the error of the last one
+    let (_, err) = failed_stages
+        .pop()
+        .expect("at least one stage has been attempted");
+    Err(err)
+}
+
+/// Moves both files back to their start and drops anything a failed stage wrote to `dest`.
+fn rewind(mut src: &File, dest: &mut File) -> Result<(), ReflinkError> {
+    src.seek(SeekFrom::Start(0)).syscall("lseek")?;
+    dest.set_len(0).syscall("ftruncate")?;
+    dest.seek(SeekFrom::Start(0)).syscall("lseek")?;
+    Ok(())
+}
+
+/// Copies `src` into `dest` with `read` and `write`.
+///
+/// `io::copy` is not used on purpose, as it uses `copy_file_range` and `sendfile` on its own on
+/// Linux.
+fn read_write(mut src: &File, mut dest: &File) -> Result<u64, ReflinkError> {
+    let mut buffer = vec![0; 128 * 1024];
+    let mut written = 0;
+    loop {
+        let read = match src.read(&mut buffer) {
+            Ok(0) => return Ok(written),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).syscall("read"),
+        };
+        dest.write_all(&buffer[..read]).syscall("write")?;
+        written += read as u64;
+    }
+}
@@ -3,8 +3,10 @@
 //! with the source file. As soon as one of the files is modified, the actual copying is done by
 //! the underlying OS.
 //!
-//! This library exposes a single function, `reflink`, which attempts to copy a file using the
+//! The main function of this library is `reflink`, which attempts to copy a file using the
 //! underlying OSs' block cloning capabilities. The function signature is identical to `std::fs::copy`.
+//! Whole directory hierarchies can be cloned with `reflink_dir`.
+//! Files which are already identical can be made to share their data with `dedupe_files`.
 //!
 //! At the moment Linux, Android, OSX, iOS, and Windows are supported.
 //!
@@ -14,19 +16,29 @@
 //!
 //! As soon as other OSes support the functionality, support will be added.
 
+mod capability_cache;
+mod clone_range;
+mod copy_outcome;
+mod dedupe;
+mod error;
+mod extents;
+mod fallback;
+mod preserve;
 mod reflink_block;
+mod reflink_dir;
+mod reflink_options;
 mod sys;
 
-use std::fs;
+use std::fs::File;
 use std::io;
-use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd};
 use std::path::Path;
 
 /// Copies a file using COW semantics.
 ///
 /// For compatibility reasons with macOS, the target file will be created using `OpenOptions::create_new`.
-/// If you want to overwrite existing files, make sure you manually delete the target file first
-/// if it exists.
+/// If you want to overwrite existing files, use [`ReflinkOptions::overwrite`].
 ///
 /// ```rust
 /// match reflink_copy::reflink("src.txt", "dest.txt") {
@@ -40,13 +52,14 @@ use std::path::Path;
 /// ## Linux / Android
 ///
 /// Uses `ioctl_ficlone`. Supported file systems include btrfs and XFS (and maybe more in the future).
-/// NOTE that it generates a temporary file and is not atomic.
+/// NOTE that it generates a temporary file and is not atomic, see [`ReflinkOptions::atomic`].
 ///
 /// ## MacOS / OS X / iOS
 ///
 /// Uses `clonefile` library function. This is supported on OS X Version >=10.12 and iOS version >= 10.0
 /// This will work on APFS partitions (which means most desktop systems are capable).
 /// If src names a directory, the directory hierarchy is cloned as if each item was cloned individually.
+/// On other platforms, use [`reflink_dir`] to clone a directory hierarchy.
 ///
 /// ## Windows
 ///
@@ -56,36 +69,10 @@ use std::path::Path;
 /// untested and probably buggy. Contributions/testers with access to a Windows Server or Dev Drives are welcome.
 /// [More Information on Dev Drives](https://learn.microsoft.com/en-US/windows/dev-drive/#how-does-dev-drive-work)
 ///
-/// NOTE that it generates a temporary file and is not atomic.
+/// NOTE that it generates a temporary file and is not atomic, see [`ReflinkOptions::atomic`].
 #[inline(always)]
-pub fn reflink(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
-    #[cfg_attr(feature = "tracing", tracing_attributes::instrument(name = "reflink"))]
-    fn inner(from: &Path, to: &Path) -> io::Result<()> {
-        sys::reflink(from, to).map_err(|err| {
-            // Linux and Windows will return an inscrutable error when `from` is a directory or a
-            // symlink, so add the real problem to the error. We need to use `fs::symlink_metadata`
-            // here because `from.is_file()` traverses symlinks.
-            //
-            // According to https://www.manpagez.com/man/2/clonefile/, Macos otoh can reflink files,
-            // directories and symlinks, so the original error is fine.
-            if !cfg!(any(
-                target_os = "macos",
-                target_os = "ios",
-                target_os = "tvos",
-                target_os = "watchos"
-            )) && !fs::symlink_metadata(from).map_or(false, |m| m.is_file())
-            {
-                io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("the source path is not an existing regular file: {}", err),
-                )
-            } else {
-                err
-            }
-        })
-    }
-
-    inner(from.as_ref(), to.as_ref())
+pub fn reflink(from: impl AsRef<Path>, to: impl AsRef<Path>) -> Result<(), ReflinkError> {
+    ReflinkOptions::new().reflink(from, to)
 }
 
 /// Attempts to reflink a file. If the operation fails, a conventional copy operation is
@@ -95,7 +82,8 @@ pub fn reflink(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
 ///
 /// If the function copied a file, the return value will be `Ok(Some(written))`.
 ///
-/// If target file already exists, operation fails with [`ErrorKind::AlreadyExists`].
+/// If target file already exists, operation fails with [`ErrorKind::AlreadyExists`]. If you want
+/// to overwrite existing files, use [`ReflinkOptions::overwrite`].
 ///
 /// ```rust
 /// match reflink_copy::reflink_or_copy("src.txt", "dest.txt") {
@@ -111,55 +99,190 @@ pub fn reflink(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
 ///
 /// If src names a directory, the directory hierarchy is cloned as if each item was cloned
 /// individually. This method does not provide a fallback for directories, so the fallback will also
-/// fail if reflinking failed. Macos supports reflinking symlinks, which is supported by the
-/// fallback.
+/// fail if reflinking failed.
+///
+/// [`ErrorKind::AlreadyExists`]: std::io::ErrorKind::AlreadyExists
+#[inline(always)]
+pub fn reflink_or_copy(
+    from: impl AsRef<Path>,
+    to: impl AsRef<Path>,
+) -> Result<Option<u64>, ReflinkError> {
+    ReflinkOptions::new().reflink_or_copy(from, to)
+}
+
+/// Attempts to reflink a file, falling back to a conventional copy operation like
+/// [`reflink_or_copy`], and reports how the target file was created.
+///
+/// Unlike [`reflink_or_copy`], the returned [`CopyOutcome`] also carries the error which made the
+/// reflink fail, so callers can record why the file had to be copied.
+///
+/// ```rust
+/// use reflink_copy::CopyOutcome;
+///
+/// match reflink_copy::reflink_or_copy_outcome("src.txt", "dest.txt") {
+///     Ok(CopyOutcome::Reflinked) => println!("file has been reflinked"),
+///     Ok(outcome) => println!(
+///         "file has been copied ({:?} bytes) because of {:?}",
+///         outcome.bytes_written(),
+///         outcome.reflink_error()
+///     ),
+///     Err(e) => println!("an error occured: {:?}", e)
+/// }
+/// ```
 #[inline(always)]
-pub fn reflink_or_copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<Option<u64>> {
+pub fn reflink_or_copy_outcome(
+    from: impl AsRef<Path>,
+    to: impl AsRef<Path>,
+) -> Result<CopyOutcome, ReflinkError> {
+    ReflinkOptions::new().reflink_or_copy_outcome(from, to)
+}
+
+/// Replaces the content of an already open file with a COW copy of the whole content of another
+/// one.
+///
+/// Unlike [`reflink`], no file is opened or created: `to` must be open for writing, and it keeps
+/// its permissions and other metadata. This allows cloning files received as handles, e.g. in a
+/// sandbox without access to their paths. To clone a part of a file only, use
+/// [`ReflinkBlockBuilder`].
+///
+/// > Note: Currently the function works only for windows and linux platforms. It returns `Err` for
+/// > any other platform.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// fn clone_handles(from: &File, to: &File) -> Result<(), reflink_copy::ReflinkError> {
+///     reflink_copy::reflink_file(from, to)
+/// }
+/// ```
+///
+/// # Implementation details per platform
+///
+/// ## Linux / Android
+///
+/// Uses `ioctl_ficlone`, like [`reflink`], and truncates `to` to the size of `from` afterwards.
+/// The kernel refuses to clone into a longer `to` if the size of `from` is not aligned to the block
+/// size, in which case `to` is truncated before cloning is retried.
+///
+/// ## MacOS / OS X / iOS
+///
+/// Not supported, `clonefile` and `fclonefileat` are only able to create a new file.
+///
+/// ## Windows
+///
+/// Resizes `to` to the size of `from` and clones it with `FSCTL_DUPLICATE_EXTENTS_TO_FILE`, like
+/// [`reflink`]. Both files must be on the same ReFS volume. If cloning fails, `to` is restored to
+/// its previous size.
+pub fn reflink_file(from: &File, to: &File) -> Result<(), ReflinkError> {
     #[cfg_attr(
         feature = "tracing",
-        tracing_attributes::instrument(name = "reflink_or_copy")
+        tracing_attributes::instrument(name = "reflink_file")
     )]
-    fn inner(from: &Path, to: &Path) -> io::Result<Option<u64>> {
-        if let Err(err) = sys::reflink(from, to) {
-            match err.kind() {
-                ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::AlreadyExists => {
-                    return Err(err);
-                }
-                _ => {}
+    fn inner(from: &File, to: &File) -> Result<(), ReflinkError> {
+        sys::reflink_file(from, to).map_err(|err| {
+            if from.metadata().is_ok_and(|m| m.is_file()) {
+                err
+            } else {
+                err.with_reason(ReflinkErrorReason::SourceNotRegularFile)
             }
+        })
+    }
 
-            #[cfg(feature = "tracing")]
-            tracing::warn!(?err, "Failed to reflink, fallback to fs::copy");
+    inner(from, to)
+}
 
-            fs::copy(from, to).map(Some).map_err(|err| {
-                // Both regular files and symlinks to regular files can be copied, so unlike
-                // `reflink` we don't want to report invalid input on both files and symlinks
-                if from.is_file() {
-                    err
-                } else {
-                    io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        format!("the source path is not an existing regular file: {}", err),
-                    )
-                }
-            })
-        } else {
-            Ok(None)
-        }
+/// Copies a file using COW semantics, like [`reflink`], with both paths resolved relative to
+/// directory handles.
+///
+/// A symlink at `src_name` or `dst_name` is never followed, so a file can be cloned from and into
+/// a directory tree which is modified concurrently by someone else, e.g. another user, without
+/// being redirected outside of it. Intermediate components of the names are still resolved
+/// normally, so pass plain file names to rule out symlinks entirely. An absolute name ignores the
+/// directory handle.
+///
+/// The target file is created like in [`reflink`] and fails with
+/// [`ErrorKind::AlreadyExists`](io::ErrorKind::AlreadyExists) if it exists. The paths attached to
+/// an error are the names, relative to their directories.
+///
+/// > Note: Currently the function works only for linux and macOS platforms. It returns `Err` for
+/// > any other unix platform, and does not exist on other platforms.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// fn main() -> Result<(), Box<dyn std::error::Error>> {
+///     let src_dir = File::open("/srv/upload")?;
+///     let dst_dir = File::open("/srv/archive")?;
+///     reflink_copy::reflinkat(&src_dir, "src.txt", &dst_dir, "dest.txt")?;
+///     Ok(())
+/// }
+/// ```
+///
+/// # Implementation details per platform
+///
+/// ## Linux / Android
+///
+/// Opens the source with `openat` and `O_NOFOLLOW`, creates the target with `openat` and
+/// `O_CREAT | O_EXCL`, and clones it with `ioctl_ficlone`. The source must be a regular file.
+///
+/// ## MacOS / OS X / iOS
+///
+/// Uses `clonefileat` with `CLONE_NOFOLLOW`, so a symlink at `src_name` is cloned as a symlink
+/// and a directory is cloned with its whole hierarchy.
+#[cfg(unix)]
+pub fn reflinkat(
+    src_dir: impl AsFd,
+    src_name: impl AsRef<Path>,
+    dst_dir: impl AsFd,
+    dst_name: impl AsRef<Path>,
+) -> Result<(), ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "reflinkat")
+    )]
+    fn inner(
+        src_dir: BorrowedFd<'_>,
+        src_name: &Path,
+        dst_dir: BorrowedFd<'_>,
+        dst_name: &Path,
+    ) -> Result<(), ReflinkError> {
+        sys::reflinkat(src_dir, src_name, dst_dir, dst_name)
+            .map_err(|err| err.with_paths(src_name, dst_name))
     }
 
-    inner(from.as_ref(), to.as_ref())
+    inner(
+        src_dir.as_fd(),
+        src_name.as_ref(),
+        dst_dir.as_fd(),
+        dst_name.as_ref(),
+    )
 }
+
 /// Checks whether reflink is supported on the filesystem for the specified source and target paths.
 ///
 /// This function verifies that both paths are on the same volume and that the filesystem supports
 /// reflink.
 ///
-/// > Note: Currently the function works only for windows. It returns `Ok(ReflinkSupport::Unknown)`
-/// > for any other platform.
+/// > Note: Currently the function works only for windows and linux. It returns
+/// > `Ok(ReflinkSupport::Unknown)` for any other platform.
+///
+/// # Implementation details per platform
+///
+/// ## Linux / Android
+///
+/// Both paths must be on the same device, except for btrfs subvolumes and overlayfs. The file
+/// system is then identified using `statfs`: btrfs and bcachefs always support reflink, while
+/// file systems such as ext4 or tmpfs never do. For other file systems such as XFS, OCFS2, NFS or
+/// overlayfs, support depends on how they were set up, so the function clones an unnamed
+/// `O_TMPFILE` of one block created in the source directory into another one in the target
+/// directory to find out. The source file itself is never opened. `ReflinkSupport::Unknown` is
+/// returned if this probe fails for other reasons, e.g. due to missing permissions.
+///
+/// The source must exist, otherwise an error of kind [`io::ErrorKind::NotFound`] is returned. The
+/// target may not exist yet, in which case its closest existing ancestor is checked.
 ///
 /// # Example
-/// ```
+/// ```no_run
 /// fn main() -> std::io::Result<()> {
 ///     let support = reflink_copy::check_reflink_support("C:\\path\\to\\file", "C:\\path\\to\\another_file")?;
 ///     println!("{support:?}");
@@ -168,15 +291,11 @@ pub fn reflink_or_copy(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Resu
 ///     Ok(())
 /// }
 /// ```
-#[cfg_attr(not(windows), allow(unused_variables))]
 pub fn check_reflink_support(
     from: impl AsRef<Path>,
     to: impl AsRef<Path>,
 ) -> io::Result<ReflinkSupport> {
-    #[cfg(windows)]
-    return sys::check_reflink_support(from, to);
-    #[cfg(not(windows))]
-    Ok(ReflinkSupport::Unknown)
+    sys::check_reflink_support(from.as_ref(), to.as_ref())
 }
 
 /// Enum indicating the reflink support status.
@@ -190,4 +309,14 @@ pub enum ReflinkSupport {
     Unknown,
 }
 
-pub use reflink_block::ReflinkBlockBuilder;
+pub use capability_cache::ReflinkCapabilityCache;
+pub use clone_range::{CloneRangeBuilder, RangeAction, RangeSegment};
+pub use copy_outcome::CopyOutcome;
+pub use dedupe::{dedupe_files, DedupeRangeBuilder, DedupeRangeResult, DedupeStatus};
+pub use error::{ReflinkError, ReflinkErrorReason};
+pub use extents::{file_extents, shared_bytes, FileExtent, SharedBytes};
+pub use fallback::FallbackStage;
+pub use preserve::XattrFilter;
+pub use reflink_block::{clone_alignment, ReflinkBlockBuilder};
+pub use reflink_dir::{reflink_dir, ReflinkDirEntry};
+pub use reflink_options::{ReflinkOptions, SymlinkPolicy};
@@ -0,0 +1,197 @@
+use crate::error::Syscall;
+use crate::{sys, ReflinkError, ReflinkErrorReason};
+use std::fs::{File, FileTimes, Metadata};
+
+/// The metadata of the source which is applied to the target, in addition to its permissions, and
+/// whether the target is synced afterwards.
+///
+/// It is configured through the `preserve_*` methods and [`ReflinkOptions::sync`]. It also tells
+/// whether the source is opened without following a symlink, as set by
+/// [`ReflinkOptions::symlink_policy`].
+///
+/// [`ReflinkOptions::sync`]: crate::ReflinkOptions::sync
+/// [`ReflinkOptions::symlink_policy`]: crate::ReflinkOptions::symlink_policy
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Preserve {
+    pub(crate) timestamps: bool,
+    pub(crate) xattrs: Option<XattrFilter>,
+    pub(crate) acls: bool,
+    pub(crate) ownership: bool,
+    pub(crate) sync: bool,
+    pub(crate) nofollow: bool,
+}
+
+impl Preserve {
+    /// Returns `true` if nothing but the permissions needs to be applied to the target.
+    pub(crate) fn is_empty(&self) -> bool {
+        *self
+            == Preserve {
+                nofollow: self.nofollow,
+                ..Preserve::default()
+            }
+    }
+
+    /// Finishes `dest` once the data of `src` has been cloned or copied into it: applies the
+    /// preserved metadata, then the permissions, and syncs it if requested.
+    ///
+    /// `metadata` must be taken from `src` before its data is read, as reading it may update its
+    /// access time.
+    pub(crate) fn finish(
+        &self,
+        src: &File,
+        metadata: &Metadata,
+        dest: &File,
+    ) -> Result<(), ReflinkError> {
+        self.apply(src, metadata, dest)?;
+        dest.set_permissions(metadata.permissions())
+            .syscall(if cfg!(windows) {
+                "SetFileInformationByHandle"
+            } else {
+                "fchmod"
+            })?;
+        if self.sync {
+            dest.sync_all().syscall(if cfg!(windows) {
+                "FlushFileBuffers"
+            } else {
+                "fsync"
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Applies the metadata of `src` to `dest`.
+    fn apply(&self, src: &File, metadata: &Metadata, dest: &File) -> Result<(), ReflinkError> {
+        // Changing the owner drops file capabilities, so it needs to happen before the extended
+        // attributes are copied
+        if self.ownership {
+            copy_ownership(metadata, dest)?;
+        }
+        if let Some(filter) = &self.xattrs {
+            sys::copy_xattrs(src, dest, filter)?;
+        }
+        if self.acls {
+            sys::copy_acls(src, dest)?;
+        }
+        // Timestamps come last, so nothing else changes them afterwards
+        if self.timestamps {
+            copy_timestamps(metadata, dest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives `dest` the owner and group recorded in `metadata`.
+fn copy_ownership(metadata: &Metadata, dest: &File) -> Result<(), ReflinkError> {
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            use std::os::unix::fs::MetadataExt;
+
+            std::os::unix::fs::fchown(dest, Some(metadata.uid()), Some(metadata.gid()))
+                .syscall("fchown")
+                .map_err(ownership_error)
+        } else {
+            let _ = (metadata, dest);
+            Ok(())
+        }
+    }
+}
+
+/// Tells apart the lack of privileges to change the owner of the target from other reasons to
+/// deny access.
+pub(crate) fn ownership_error(err: ReflinkError) -> ReflinkError {
+    if err.kind() == std::io::ErrorKind::PermissionDenied {
+        err.with_reason(ReflinkErrorReason::OwnershipNotPermitted)
+    } else {
+        err
+    }
+}
+
+/// Selects the extended attributes copied by
+/// [`ReflinkOptions::preserve_xattrs`](crate::ReflinkOptions::preserve_xattrs), by namespace.
+///
+/// The namespace of an attribute is the part of its name before the first dot, e.g. `user` for
+/// `user.mime_type` or `security` for `security.selinux`. An attribute is copied if its namespace
+/// has been included, or if no namespace has been included at all, and if its namespace has not
+/// been excluded.
+///
+/// The attributes holding POSIX ACLs are never copied this way, regardless of the filter. Use
+/// [`ReflinkOptions::preserve_acls`](crate::ReflinkOptions::preserve_acls) instead.
+///
+/// # Example
+///
+/// Copy everything except the SELinux label and file capabilities:
+///
+/// ```no_run
+/// use reflink_copy::{ReflinkOptions, XattrFilter};
+///
+/// fn clone() -> Result<(), reflink_copy::ReflinkError> {
+///     ReflinkOptions::new()
+///         .preserve_xattrs(Some(XattrFilter::all().exclude_namespace("security")))
+///         .reflink("src.txt", "dest.txt")
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct XattrFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl XattrFilter {
+    /// Creates a filter matching all extended attributes.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Restricts the copied attributes to the ones in `namespace`, in addition to any namespace
+    /// included before.
+    #[must_use]
+    pub fn include_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.include.push(namespace.into());
+        self
+    }
+
+    /// Skips the attributes in `namespace`.
+    #[must_use]
+    pub fn exclude_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.exclude.push(namespace.into());
+        self
+    }
+
+    /// Returns whether the attribute `name` should be copied.
+    #[allow(dead_code)] // only used on Linux
+    pub(crate) fn matches(&self, name: &[u8]) -> bool {
+        let namespace = name.split(|&byte| byte == b'.').next().unwrap_or_default();
+        let is_namespace = |candidate: &String| candidate.as_bytes() == namespace;
+
+        (self.include.is_empty() || self.include.iter().any(is_namespace))
+            && !self.exclude.iter().any(is_namespace)
+    }
+}
+
+/// Sets the access, modification and, where supported, creation times of `dest` to the ones
+/// recorded in `metadata`.
+fn copy_timestamps(metadata: &Metadata, dest: &File) -> Result<(), ReflinkError> {
+    #[allow(unused_mut)]
+    let mut times = FileTimes::new()
+        .set_accessed(metadata.accessed().syscall("fstat")?)
+        .set_modified(metadata.modified().syscall("fstat")?);
+
+    cfg_if::cfg_if! {
+        if #[cfg(windows)] {
+            use std::os::windows::fs::FileTimesExt;
+            times = times.set_created(metadata.created().syscall("GetFileInformationByHandle")?);
+        } else if #[cfg(target_os = "macos")] {
+            use std::os::macos::fs::FileTimesExt;
+            times = times.set_created(metadata.created().syscall("fstat")?);
+        } else if #[cfg(target_os = "ios")] {
+            use std::os::ios::fs::FileTimesExt;
+            times = times.set_created(metadata.created().syscall("fstat")?);
+        }
+    }
+
+    dest.set_times(times).syscall(if cfg!(windows) {
+        "SetFileInformationByHandle"
+    } else {
+        "futimens"
+    })
+}
@@ -1,18 +1,61 @@
-use crate::sys;
+use crate::error::Syscall;
+use crate::{sys, ReflinkError, ReflinkErrorReason};
 use std::fs::File;
-use std::io;
 use std::num::NonZeroU64;
 
+/// Returns the granularity in bytes at which the file system of `file` clones data, i.e. the
+/// cluster size the ranges passed to [`ReflinkBlockBuilder`] must be aligned to.
+///
+/// > Note: Currently the function works only for windows and linux platforms. It returns `Err` for
+/// > any other platform.
+///
+/// ```no_run
+/// use std::fs::File;
+///
+/// fn alignment() -> Result<(), Box<dyn std::error::Error>> {
+///     let file = File::open("source.bin")?;
+///     let cluster_size = reflink_copy::clone_alignment(&file)?;
+///     println!("blocks must be aligned to {} bytes", cluster_size);
+///     Ok(())
+/// }
+/// ```
+///
+/// # Implementation details per platform
+///
+/// ## Linux / Android
+///
+/// Uses the fragment size `f_frsize` reported by `fstatfs`, or the `st_blksize` reported by
+/// `fstat` if the file system reports none. Linux has no interface to query the clone granularity
+/// itself, so the value is a conservative hint: the file system may also accept smaller
+/// alignments, e.g. for a block which ends at the end of the source file.
+///
+/// ## Windows
+///
+/// Uses the `ClusterSizeInBytes` returned by `FSCTL_GET_INTEGRITY_INFORMATION`, which is only
+/// available on ReFS.
+pub fn clone_alignment(file: &File) -> Result<NonZeroU64, ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "clone_alignment")
+    )]
+    fn inner(file: &File) -> Result<NonZeroU64, ReflinkError> {
+        sys::clone_alignment(file)
+    }
+
+    inner(file)
+}
+
 /// Creates a reflink of a specified block from one file to another.
 ///
 /// This functionality is designed to be highly performant and does not perform any extra API calls.
-/// It is expected that the user takes care of necessary preliminary checks and preparations.
+/// It is expected that the user takes care of necessary preliminary checks and preparations, or
+/// opts in to have them performed with [`ReflinkBlockBuilder::validate`].
 ///
 /// If you need to clone an entire file, consider using the [`reflink`] or [`reflink_or_copy`]
 /// functions instead.
 ///
 /// > Note: Currently the function works only for windows and linux platforms. It returns `Err` for
-///   any other platform.
+/// > any other platform.
 ///
 /// # General restrictions
 ///
@@ -50,9 +93,9 @@ use std::num::NonZeroU64;
 ///   and a cluster size of 4096 bytes, `src_length` should be 8192 bytes.
 ///
 /// > Note: In order to handle blocks larger than 4GB,
-///   [`ReflinkBlockBuilder::reflink_block`] splits these big blocks into smaller ones.
-///   Each smaller block is 4GB minus the cluster size. This means there might be more than one API
-///   call needed for the larger blocks.
+/// > [`ReflinkBlockBuilder::reflink_block`] splits these big blocks into smaller ones.
+/// > Each smaller block is 4GB minus the cluster size. This means there might be more than one API
+/// > call needed for the larger blocks.
 ///
 /// More information about block cloning on Windows can be found by the
 /// [link](https://learn.microsoft.com/en-us/windows/win32/fileio/block-cloning).
@@ -85,6 +128,26 @@ use std::num::NonZeroU64;
 ///     Ok(())
 /// }
 /// ```
+///
+/// The cluster size does not need to be known in advance, [`clone_alignment`] queries it from the
+/// file system:
+/// ```no_run
+/// use std::fs::File;
+///
+/// fn clone_first_cluster() -> Result<(), Box<dyn std::error::Error>> {
+///     let from_file = File::open("source.bin")?;
+///     let to_file = File::create("destination.bin")?;
+///     let cluster_size = reflink_copy::clone_alignment(&from_file)?;
+///
+///     to_file.set_len(cluster_size.get())?;
+///
+///     reflink_copy::ReflinkBlockBuilder::new(&from_file, &to_file, cluster_size)
+///         .cluster_size(cluster_size)
+///         .reflink_block()?;
+///
+///     Ok(())
+/// }
+/// ```
 /// [`reflink`]: crate::reflink
 /// [`reflink_or_copy`]: crate::reflink_or_copy
 #[derive(Debug)]
@@ -95,6 +158,8 @@ pub struct ReflinkBlockBuilder<'from, 'to> {
     to_offset: u64,
     src_length: u64,
     cluster_size: Option<NonZeroU64>,
+    auto_cluster_size: bool,
+    validate: bool,
 }
 
 impl<'from, 'to> ReflinkBlockBuilder<'from, 'to> {
@@ -107,6 +172,8 @@ impl<'from, 'to> ReflinkBlockBuilder<'from, 'to> {
             to_offset: 0,
             src_length: src_length.get(),
             cluster_size: None,
+            auto_cluster_size: false,
+            validate: false,
         }
     }
 
@@ -132,15 +199,124 @@ impl<'from, 'to> ReflinkBlockBuilder<'from, 'to> {
         self
     }
 
+    /// Sets the option to query the cluster size of the source with [`clone_alignment`] when
+    /// [`ReflinkBlockBuilder::reflink_block`] is called, unless it has been set with
+    /// [`ReflinkBlockBuilder::cluster_size`].
+    ///
+    /// This costs an extra API call per block, so prefer calling [`clone_alignment`] once when
+    /// cloning many blocks.
+    #[must_use]
+    pub fn auto_cluster_size(mut self, auto_cluster_size: bool) -> Self {
+        self.auto_cluster_size = auto_cluster_size;
+        self
+    }
+
+    /// Sets the option to check the block against the restrictions listed above before
+    /// reflinking it, so that a misuse is reported with a descriptive error instead of the
+    /// generic one returned by the OS.
+    ///
+    /// The block is rejected with [`ReflinkErrorReason::Misaligned`] if it is not aligned to the
+    /// cluster size, which is queried with [`clone_alignment`] unless it has been set with
+    /// [`ReflinkBlockBuilder::cluster_size`]. It is rejected with
+    /// [`ReflinkErrorReason::InvalidRange`] if
+    ///
+    /// - its end overflows the maximum file offset,
+    /// - it overlaps with the destination block in the same file,
+    /// - on Windows, the destination block extends past the end of the destination file.
+    ///
+    /// A zero length is already ruled out by [`ReflinkBlockBuilder::new`].
+    ///
+    /// The validation costs a few extra API calls per block.
+    #[must_use]
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
     /// Performs reflink operation for the specified block of data.
-    pub fn reflink_block(self) -> io::Result<()> {
+    ///
+    /// Errors caused by blocks which are not aligned to the cluster size are reported as
+    /// [`ReflinkErrorReason::Misaligned`].
+    pub fn reflink_block(self) -> Result<(), ReflinkError> {
+        let cluster_size = match self.cluster_size {
+            None if self.auto_cluster_size || self.validate => Some(clone_alignment(self.from)?),
+            cluster_size => cluster_size,
+        };
+        if let (true, Some(cluster_size)) = (self.validate, cluster_size) {
+            self.check(cluster_size.get())?;
+        }
+
         sys::reflink_block(
             self.from,
             self.from_offset,
             self.to,
             self.to_offset,
             self.src_length,
-            self.cluster_size,
+            cluster_size,
         )
     }
+
+    /// Checks the block against the restrictions of the platform, see
+    /// [`ReflinkBlockBuilder::validate`].
+    // `u64::is_multiple_of` requires Rust 1.87
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn check(&self, cluster_size: u64) -> Result<(), ReflinkError> {
+        let invalid = |reason, msg| Err(ReflinkError::invalid_input(reason, msg));
+
+        // Both platforms take signed offsets
+        let end = |offset: u64| {
+            offset
+                .checked_add(self.src_length)
+                .filter(|&end| end <= i64::MAX as u64)
+        };
+        let (from_end, to_end) = match (end(self.from_offset), end(self.to_offset)) {
+            (Some(from_end), Some(to_end)) => (from_end, to_end),
+            _ => {
+                return invalid(
+                    ReflinkErrorReason::InvalidRange,
+                    "the end of the block overflows the maximum file offset",
+                )
+            }
+        };
+
+        if self.from_offset % cluster_size != 0 || self.to_offset % cluster_size != 0 {
+            return invalid(
+                ReflinkErrorReason::Misaligned,
+                "the offsets of the block are not aligned to the cluster size",
+            );
+        }
+        // Linux accepts a block which ends at the end of the source file, Windows only whole
+        // clusters
+        if self.src_length % cluster_size != 0
+            && (cfg!(windows) || from_end != self.from.metadata().syscall("fstat")?.len())
+        {
+            return invalid(
+                ReflinkErrorReason::Misaligned,
+                "the length of the block is not aligned to the cluster size",
+            );
+        }
+
+        if self.from_offset < to_end
+            && self.to_offset < from_end
+            && sys::same_file(self.from, self.to).syscall("fstat")?
+        {
+            return invalid(
+                ReflinkErrorReason::InvalidRange,
+                "the source and destination blocks overlap within the same file",
+            );
+        }
+
+        // The last cluster of the destination may be cloned as a whole
+        if cfg!(windows) {
+            let to_len = self.to.metadata().syscall("fstat")?.len();
+            if to_end > to_len.div_ceil(cluster_size) * cluster_size {
+                return invalid(
+                    ReflinkErrorReason::InvalidRange,
+                    "the destination block extends past the end of the destination file",
+                );
+            }
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,203 @@
+use crate::error::Syscall;
+use crate::{ReflinkError, ReflinkErrorReason};
+use std::fs::{self, FileType};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Recursively reflinks a directory hierarchy.
+///
+/// `to` is created with `create_dir` and must not exist yet, mirroring the behaviour of [`reflink`]
+/// for files. Every directory below `from` is recreated, every regular file is cloned with
+/// [`reflink`], and every symlink is recreated pointing to the same target. Directory permissions
+/// are copied after their content has been cloned, so read-only directories are handled as well.
+///
+/// A failure on a single entry does not stop the walk. Instead, the returned list contains one
+/// [`ReflinkDirEntry`] per visited entry, each carrying its own result. If a directory cannot be
+/// created or read, its content is skipped.
+///
+/// The function itself fails if `from` is not a directory, if `to` is `from` or lies inside of it,
+/// if `to` cannot be created, if the entries of `from` cannot be read, or if the permissions of
+/// `to` cannot be set. In the latter two cases, the part of the tree created so far is left
+/// behind. These errors carry both paths.
+///
+/// ```no_run
+/// match reflink_copy::reflink_dir("src", "dest") {
+///     Ok(entries) => {
+///         for entry in entries.iter().filter(|entry| entry.error().is_some()) {
+///             println!("failed to reflink {:?}: {:?}", entry.from(), entry.error());
+///         }
+///     }
+///     Err(e) => println!("error while reflinking: {:?}", e),
+/// }
+/// ```
+///
+/// # Implementation details per platform
+///
+/// ## MacOS / OS X / iOS
+///
+/// [`reflink`] is able to clone a whole directory hierarchy in a single `clonefile` call, which
+/// is faster but does not report per-entry results.
+///
+/// ## Windows
+///
+/// Creating symlinks requires either administrator privileges or the developer mode to be enabled.
+///
+/// [`reflink`]: crate::reflink
+pub fn reflink_dir(
+    from: impl AsRef<Path>,
+    to: impl AsRef<Path>,
+) -> Result<Vec<ReflinkDirEntry>, ReflinkError> {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing_attributes::instrument(name = "reflink_dir")
+    )]
+    fn inner(from: &Path, to: &Path) -> Result<Vec<ReflinkDirEntry>, ReflinkError> {
+        let metadata = fs::symlink_metadata(from).syscall("lstat")?;
+        if !metadata.is_dir() {
+            return Err(ReflinkError::invalid_input(
+                ReflinkErrorReason::Other,
+                "the source path is not a directory",
+            ));
+        }
+        // Like `cp -r`, refuse to copy a directory into itself, which would never end
+        if is_inside(from, to)? {
+            return Err(ReflinkError::invalid_input(
+                ReflinkErrorReason::Other,
+                "the destination path is inside the source directory",
+            ));
+        }
+
+        fs::create_dir(to).syscall("mkdir")?;
+
+        let mut entries = Vec::new();
+        walk_dir(from, to, &mut entries)?;
+        fs::set_permissions(to, metadata.permissions()).syscall("chmod")?;
+
+        Ok(entries)
+    }
+
+    let (from, to) = (from.as_ref(), to.as_ref());
+    inner(from, to).map_err(|err| err.with_paths(from, to))
+}
+
+/// The result of reflinking a single entry with [`reflink_dir`].
+#[derive(Debug)]
+pub struct ReflinkDirEntry {
+    from: PathBuf,
+    to: PathBuf,
+    file_type: FileType,
+    result: Result<(), ReflinkError>,
+}
+
+impl ReflinkDirEntry {
+    /// Returns the path of the source entry.
+    pub fn from(&self) -> &Path {
+        &self.from
+    }
+
+    /// Returns the path of the destination entry.
+    pub fn to(&self) -> &Path {
+        &self.to
+    }
+
+    /// Returns the file type of the source entry. Symlinks are not followed.
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    /// Returns the error if the entry could not be reflinked.
+    pub fn error(&self) -> Option<&ReflinkError> {
+        self.result.as_ref().err()
+    }
+
+    /// Consumes the entry and returns its result.
+    pub fn into_result(self) -> Result<(), ReflinkError> {
+        self.result
+    }
+}
+
+/// Returns whether the not yet existing `to` would be `from` or lie inside of it.
+fn is_inside(from: &Path, to: &Path) -> Result<bool, ReflinkError> {
+    let from = fs::canonicalize(from).syscall("realpath")?;
+    let parent = match to.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // If `to` has no name or its parent does not exist, creating it fails anyway
+    let (Some(name), Ok(parent)) = (to.file_name(), fs::canonicalize(parent)) else {
+        return Ok(false);
+    };
+    Ok(parent.join(name).starts_with(from))
+}
+
+fn walk_dir(
+    from: &Path,
+    to: &Path,
+    entries: &mut Vec<ReflinkDirEntry>,
+) -> Result<(), ReflinkError> {
+    for dir_entry in fs::read_dir(from).syscall("readdir")? {
+        let dir_entry = dir_entry.syscall("readdir")?;
+        let file_type = dir_entry.file_type().syscall("lstat")?;
+        let from = dir_entry.path();
+        let to = to.join(dir_entry.file_name());
+
+        let result = if file_type.is_dir() {
+            reflink_subdir(&from, &to, entries)
+        } else if file_type.is_symlink() {
+            copy_symlink(&from, &to)
+        } else if file_type.is_file() {
+            crate::reflink(&from, &to)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the source path is neither a regular file, a directory nor a symlink",
+            ))
+            .syscall("reflink")
+            .map_err(|err| err.with_reason(ReflinkErrorReason::SourceNotRegularFile))
+        }
+        .map_err(|err| err.with_paths(&from, &to));
+
+        entries.push(ReflinkDirEntry {
+            from,
+            to,
+            file_type,
+            result,
+        });
+    }
+
+    Ok(())
+}
+
+fn reflink_subdir(
+    from: &Path,
+    to: &Path,
+    entries: &mut Vec<ReflinkDirEntry>,
+) -> Result<(), ReflinkError> {
+    let permissions = fs::symlink_metadata(from).syscall("lstat")?.permissions();
+    fs::create_dir(to).syscall("mkdir")?;
+    walk_dir(from, to, entries)?;
+    // Permissions are applied last, a read-only directory would reject its content otherwise.
+    fs::set_permissions(to, permissions).syscall("chmod")
+}
+
+/// Creates a symlink at `to` pointing to the target of the symlink `from`.
+pub(crate) fn copy_symlink(from: &Path, to: &Path) -> Result<(), ReflinkError> {
+    let target = fs::read_link(from).syscall("readlink")?;
+
+    cfg_if::cfg_if! {
+        if #[cfg(unix)] {
+            std::os::unix::fs::symlink(target, to).syscall("symlink")
+        } else if #[cfg(windows)] {
+            use std::os::windows::fs::FileTypeExt;
+
+            if fs::symlink_metadata(from).syscall("lstat")?.file_type().is_symlink_dir() {
+                std::os::windows::fs::symlink_dir(target, to).syscall("CreateSymbolicLinkW")
+            } else {
+                std::os::windows::fs::symlink_file(target, to).syscall("CreateSymbolicLinkW")
+            }
+        } else {
+            let _ = (target, to);
+            Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("symlink")
+        }
+    }
+}
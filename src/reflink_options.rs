@@ -0,0 +1,656 @@
+use crate::error::Syscall;
+use crate::preserve::Preserve;
+use crate::reflink_dir::copy_symlink;
+use crate::{
+    fallback, sys, CopyOutcome, FallbackStage, ReflinkError, ReflinkErrorReason, XattrFilter,
+};
+use std::path::Path;
+use std::{fs, io};
+
+/// Options and flags which can be used to configure how a file is reflinked.
+///
+/// This builder exposes the ability to configure how [`reflink`] and [`reflink_or_copy`] behave.
+/// The free functions are equivalent to calling the corresponding methods on
+/// `ReflinkOptions::new()`.
+///
+/// Generally speaking, when using `ReflinkOptions`, you'll first call [`ReflinkOptions::new`],
+/// then chain calls to methods to set each option, then call [`ReflinkOptions::reflink`] or
+/// [`ReflinkOptions::reflink_or_copy`], passing the paths of the files you're trying to clone.
+/// Like `std::fs::OpenOptions`, a set of options can be reused for any number of files.
+///
+/// The available options are:
+///
+/// - how the target is created: [`overwrite`](ReflinkOptions::overwrite) and
+///   [`atomic`](ReflinkOptions::atomic),
+/// - how a symlink source is handled: [`symlink_policy`](ReflinkOptions::symlink_policy), or
+///   its shorthand [`follow_symlinks`](ReflinkOptions::follow_symlinks),
+/// - how [`reflink_or_copy`](ReflinkOptions::reflink_or_copy) copies the file if it cannot be
+///   reflinked: [`fallback`](ReflinkOptions::fallback),
+/// - which metadata is preserved besides the permissions:
+///   [`preserve_timestamps`](ReflinkOptions::preserve_timestamps),
+///   [`preserve_xattrs`](ReflinkOptions::preserve_xattrs),
+///   [`preserve_acls`](ReflinkOptions::preserve_acls),
+///   [`preserve_ownership`](ReflinkOptions::preserve_ownership), or all of them at once with
+///   [`preserve_all`](ReflinkOptions::preserve_all),
+/// - whether the target is durable once the call returns: [`sync`](ReflinkOptions::sync).
+///
+/// # Examples
+///
+/// Replace an existing file with a reflink of another one:
+///
+/// ```no_run
+/// use reflink_copy::ReflinkOptions;
+///
+/// fn replace() -> Result<(), reflink_copy::ReflinkError> {
+///     ReflinkOptions::new()
+///         .overwrite(true)
+///         .reflink("src.txt", "dest.txt")
+/// }
+/// ```
+///
+/// Clone or copy a file like `cp -p`, and make sure it survives a crash:
+///
+/// ```no_run
+/// use reflink_copy::ReflinkOptions;
+///
+/// fn archive() -> Result<(), reflink_copy::ReflinkError> {
+///     let mut options = ReflinkOptions::new();
+///     options.atomic(true).preserve_timestamps(true).sync(true);
+///
+///     options.reflink_or_copy("src.txt", "archive/src.txt")?;
+///     options.reflink_or_copy("other.txt", "archive/other.txt")?;
+///     Ok(())
+/// }
+/// ```
+///
+/// [`reflink`]: crate::reflink
+/// [`reflink_or_copy`]: crate::reflink_or_copy
+#[derive(Clone, Debug)]
+pub struct ReflinkOptions {
+    overwrite: bool,
+    atomic: bool,
+    symlink_policy: SymlinkPolicy,
+    fallback: Option<Vec<FallbackStage>>,
+    preserve: Preserve,
+}
+
+impl Default for ReflinkOptions {
+    fn default() -> Self {
+        Self {
+            overwrite: false,
+            atomic: false,
+            symlink_policy: SymlinkPolicy::Follow,
+            fallback: None,
+            preserve: Preserve::default(),
+        }
+    }
+}
+
+impl ReflinkOptions {
+    /// Creates a blank new set of options ready for configuration.
+    ///
+    /// All options are initially set to `false`, symlinks are followed, and
+    /// [`ReflinkOptions::reflink_or_copy`] falls back to `std::fs::copy`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the option to replace the target file if it already exists.
+    ///
+    /// When set, the source is cloned (or copied) into a temporary file next to the target, which
+    /// is then renamed over the target. Readers therefore either observe the old content or the
+    /// complete new one, never a missing or partially written target.
+    ///
+    /// When unset, the target is created with `OpenOptions::create_new` and the operation fails
+    /// with [`ErrorKind::AlreadyExists`] if it exists.
+    ///
+    /// [`ErrorKind::AlreadyExists`]: std::io::ErrorKind::AlreadyExists
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    /// Sets the option to create the target file atomically.
+    ///
+    /// When set, the target only appears once it has been fully cloned (or copied) and its
+    /// permissions have been set. If the process dies halfway, no partially written target is left
+    /// behind. The target must still not exist, unless [`ReflinkOptions::overwrite`] is set, which
+    /// is atomic on its own.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// The source is cloned, or copied by [`ReflinkOptions::reflink_or_copy`], into an unnamed
+    /// `O_TMPFILE` in the target directory, which is linked into place with `linkat` afterwards.
+    /// If the file system does not support `O_TMPFILE`, a hidden temporary file is used instead.
+    /// As `std::fs::copy` can only create named files, the copy uses the stages set by
+    /// [`ReflinkOptions::fallback`], or `copy_file_range`, `sendfile` and `read`/`write` by
+    /// default.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// `clonefile` is atomic on its own, so this option makes no difference for reflinks.
+    ///
+    /// ## Windows
+    ///
+    /// The source is cloned into a hidden temporary file in the target directory, which is hard
+    /// linked into place afterwards.
+    pub fn atomic(&mut self, atomic: bool) -> &mut Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Sets how the source is handled if it is a symlink, see [`SymlinkPolicy`]. Symlinks in the
+    /// parent directories of the source are followed regardless of the policy.
+    ///
+    /// The default is [`SymlinkPolicy::Follow`].
+    pub fn symlink_policy(&mut self, symlink_policy: SymlinkPolicy) -> &mut Self {
+        self.symlink_policy = symlink_policy;
+        self.preserve.nofollow = symlink_policy != SymlinkPolicy::Follow;
+        self
+    }
+
+    /// Sets the option to follow the source if it is a symlink. It is set by default.
+    ///
+    /// This is a shorthand for [`ReflinkOptions::symlink_policy`]: setting it selects
+    /// [`SymlinkPolicy::Follow`], unsetting it [`SymlinkPolicy::Error`].
+    pub fn follow_symlinks(&mut self, follow_symlinks: bool) -> &mut Self {
+        self.symlink_policy(if follow_symlinks {
+            SymlinkPolicy::Follow
+        } else {
+            SymlinkPolicy::Error
+        })
+    }
+
+    /// Sets the stages [`ReflinkOptions::reflink_or_copy`] tries, in order, if reflinking fails.
+    ///
+    /// By default, the fallback is `std::fs::copy`, which picks the best way to copy on its own
+    /// but does not tell which one it used. Sparse files are copied with [`FallbackStage::Sparse`]
+    /// instead, as `std::fs::copy` would fill their holes.
+    ///
+    /// With an explicit ladder, each stage is attempted until one succeeds, and
+    /// [`ReflinkOptions::reflink_or_copy_outcome`] reports the successful stage along with the
+    /// errors of the stages which failed before it. The target is truncated before each stage. If
+    /// all stages fail, the error of the last one is returned, and an empty ladder disables the
+    /// fallback altogether.
+    ///
+    /// Stages which are not available on the current platform fail with
+    /// [`ReflinkErrorReason::NotSupported`], so the same ladder can be used everywhere.
+    ///
+    /// ```no_run
+    /// use reflink_copy::{FallbackStage, ReflinkOptions};
+    ///
+    /// fn copy() -> Result<(), reflink_copy::ReflinkError> {
+    ///     let outcome = ReflinkOptions::new()
+    ///         .fallback(&[
+    ///             FallbackStage::CopyFileRange,
+    ///             FallbackStage::Sendfile,
+    ///             FallbackStage::ReadWrite,
+    ///         ])
+    ///         .reflink_or_copy_outcome("src.txt", "dest.txt")?;
+    ///     for (stage, err) in outcome.failed_stages() {
+    ///         println!("{:?} failed: {}", stage, err);
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn fallback(&mut self, stages: &[FallbackStage]) -> &mut Self {
+        self.fallback = Some(stages.to_vec());
+        self
+    }
+
+    /// Sets the option to give the target file the access and modification times of the source.
+    ///
+    /// Without this option, the target gets the current time as its modification time, so build
+    /// systems comparing timestamps consider it changed. The times are set on the open target
+    /// after its data has been cloned or copied, and before it is moved into place.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// Uses `futimens`. Linux does not allow setting the creation time.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// `clonefile` copies the timestamps on its own, including the creation time. The copy
+    /// fallback sets the creation time as well.
+    ///
+    /// ## Windows
+    ///
+    /// Uses `SetFileInformationByHandle`, which also sets the creation time.
+    pub fn preserve_timestamps(&mut self, preserve_timestamps: bool) -> &mut Self {
+        self.preserve.timestamps = preserve_timestamps;
+        self
+    }
+
+    /// Sets the option to copy the extended attributes of the source selected by `filter` to the
+    /// target. `None`, the default, copies none of them.
+    ///
+    /// Reflinks only share the data of a file, so without this option SELinux labels, `user.*`
+    /// metadata and file capabilities are lost. The attributes are copied from the open source to
+    /// the open target, before the target is moved into place. Copying attributes in the
+    /// `security` or `trusted` namespaces usually requires privileges, so exclude them with
+    /// [`XattrFilter::exclude_namespace`] when running unprivileged.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// Uses `flistxattr`, `fgetxattr` and `fsetxattr`.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// `clonefile` copies all extended attributes on its own, regardless of `filter`. The copy
+    /// fallback does not copy them.
+    ///
+    /// ## Windows
+    ///
+    /// Extended attributes are not copied.
+    pub fn preserve_xattrs(&mut self, filter: Option<XattrFilter>) -> &mut Self {
+        self.preserve.xattrs = filter;
+        self
+    }
+
+    /// Sets the option to give the target file the same access ACL as the source.
+    ///
+    /// Like any new file, the target inherits the default ACL of its directory, if any, when it
+    /// is created. Its permission bits are then set to the ones of the source, which also limits
+    /// the permissions granted by an inherited ACL through its mask entry. This is what happens
+    /// when the option is unset.
+    ///
+    /// When set, the ACL of the source replaces the inherited one, before the target is moved into
+    /// place. If the source has no ACL, the inherited one is removed, so that only the permission
+    /// bits apply to the target, as they do to the source.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// Copies the `system.posix_acl_access` extended attribute. Fails if the source has an ACL
+    /// but the file system of the target does not support ACLs.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// `clonefile` copies the ACL on its own. The copy fallback does not copy it.
+    ///
+    /// ## Windows
+    ///
+    /// ACLs are not copied, the target always inherits the ACL of its directory.
+    pub fn preserve_acls(&mut self, preserve_acls: bool) -> &mut Self {
+        self.preserve.acls = preserve_acls;
+        self
+    }
+
+    /// Sets the option to give the target file the owner and group of the source.
+    ///
+    /// Without this option, the target is owned by the user running the process, as with any new
+    /// file. Changing the owner requires privileges, e.g. running as root or `CAP_CHOWN` on Linux.
+    /// If the process lacks them, the operation fails with
+    /// [`ReflinkErrorReason::OwnershipNotPermitted`], and [`ReflinkOptions::reflink_or_copy`]
+    /// does not fall back to a copy, which would fail the same way.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// Uses `fchown` on the target before the other metadata is applied, as changing the owner
+    /// removes file capabilities and the setuid and setgid bits.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// Calls `clonefile` without `CLONE_NOOWNERCOPY`. As `clonefile` silently keeps the caller as
+    /// the owner when unprivileged, the owner of the clone is checked afterwards. The copy
+    /// fallback uses `fchown`.
+    ///
+    /// ## Windows
+    ///
+    /// The owner is not copied.
+    pub fn preserve_ownership(&mut self, preserve_ownership: bool) -> &mut Self {
+        self.preserve.ownership = preserve_ownership;
+        self
+    }
+
+    /// Sets all the `preserve_*` options at once.
+    ///
+    /// Extended attributes are preserved with [`XattrFilter::all`]. Keep in mind that
+    /// [`preserve_ownership`](ReflinkOptions::preserve_ownership) requires privileges, so call it
+    /// with `false` afterwards when running unprivileged.
+    pub fn preserve_all(&mut self, preserve: bool) -> &mut Self {
+        self.preserve_timestamps(preserve)
+            .preserve_xattrs(if preserve {
+                Some(XattrFilter::all())
+            } else {
+                None
+            })
+            .preserve_acls(preserve)
+            .preserve_ownership(preserve)
+    }
+
+    /// Sets the option to flush the target to disk before it is moved into place, and its parent
+    /// directory once it is in place.
+    ///
+    /// Without this option, the cloned or copied data and metadata, as well as the directory entry
+    /// of the target, may still be in the caches of the operating system when the call returns,
+    /// and get lost on a crash or power failure. With it, the target is durable once the call
+    /// returns `Ok`, whether it has been reflinked or copied.
+    ///
+    /// # Implementation details per platform
+    ///
+    /// ## Linux / Android
+    ///
+    /// Uses `fsync` on the target and on its parent directory.
+    ///
+    /// ## MacOS / OS X / iOS
+    ///
+    /// Opens the clone created by `clonefile` to `fsync` it, then uses `fsync` on the parent
+    /// directory.
+    ///
+    /// ## Windows
+    ///
+    /// Uses `FlushFileBuffers` on the target. The parent directory is not flushed, as that
+    /// requires write access to it, and NTFS and ReFS journal the directory entries anyway.
+    pub fn sync(&mut self, sync: bool) -> &mut Self {
+        self.preserve.sync = sync;
+        self
+    }
+
+    /// Copies a file using COW semantics with the options specified by `self`.
+    ///
+    /// See [`reflink`](crate::reflink) for the platform specific details.
+    pub fn reflink(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> Result<(), ReflinkError> {
+        #[cfg_attr(feature = "tracing", tracing_attributes::instrument(name = "reflink"))]
+        fn inner(options: &ReflinkOptions, from: &Path, to: &Path) -> Result<(), ReflinkError> {
+            if options
+                .check_source(from)
+                .map_err(|err| err.with_paths(from, to))?
+            {
+                return options
+                    .copy_link(from, to)
+                    .map_err(|err| err.with_paths(from, to));
+            }
+
+            let result = if options.atomic && !options.overwrite {
+                sys::reflink_atomic(from, to, &options.preserve)
+            } else {
+                options.create_target(to, |to| sys::reflink(from, to, &options.preserve))
+            };
+            let result = match result {
+                Err(err) if options.is_replaced_by_symlink(&err, from) => {
+                    options.copy_link(from, to)
+                }
+                result => result,
+            };
+
+            result.map_err(|err| {
+                // Linux and Windows will return an inscrutable error when `from` is a
+                // directory, so add the real problem to the error. A symlink source has been
+                // handled by the symlink policy already, so it is fine to traverse it here.
+                //
+                // According to https://www.manpagez.com/man/2/clonefile/, Macos otoh can
+                // reflink files, directories and symlinks, so the original error is fine.
+                let err = if !cfg!(any(
+                    target_os = "macos",
+                    target_os = "ios",
+                    target_os = "tvos",
+                    target_os = "watchos"
+                )) && !fs::metadata(from).is_ok_and(|m| m.is_file())
+                {
+                    err.with_reason(ReflinkErrorReason::SourceNotRegularFile)
+                } else {
+                    err
+                };
+                err.with_paths(from, to)
+            })?;
+
+            options
+                .sync_parent(to)
+                .map_err(|err| err.with_paths(from, to))
+        }
+
+        inner(self, from.as_ref(), to.as_ref())
+    }
+
+    /// Attempts to reflink a file with the options specified by `self`. If the operation fails, a
+    /// conventional copy operation is attempted as a fallback.
+    ///
+    /// See [`reflink_or_copy`](crate::reflink_or_copy) for the meaning of the return value.
+    pub fn reflink_or_copy(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> Result<Option<u64>, ReflinkError> {
+        #[cfg_attr(
+            feature = "tracing",
+            tracing_attributes::instrument(name = "reflink_or_copy")
+        )]
+        fn inner(
+            options: &ReflinkOptions,
+            from: &Path,
+            to: &Path,
+        ) -> Result<Option<u64>, ReflinkError> {
+            options
+                .reflink_or_copy_inner(from, to, false)
+                .map(|outcome| outcome.bytes_written())
+        }
+
+        inner(self, from.as_ref(), to.as_ref())
+    }
+
+    /// Attempts to reflink a file with the options specified by `self`, falling back to a
+    /// conventional copy operation, and reports how the target was created.
+    ///
+    /// See [`reflink_or_copy_outcome`](crate::reflink_or_copy_outcome) for details.
+    pub fn reflink_or_copy_outcome(
+        &self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+    ) -> Result<CopyOutcome, ReflinkError> {
+        #[cfg_attr(
+            feature = "tracing",
+            tracing_attributes::instrument(name = "reflink_or_copy_outcome")
+        )]
+        fn inner(
+            options: &ReflinkOptions,
+            from: &Path,
+            to: &Path,
+        ) -> Result<CopyOutcome, ReflinkError> {
+            options.reflink_or_copy_inner(from, to, false)
+        }
+
+        inner(self, from.as_ref(), to.as_ref())
+    }
+
+    /// Attempts to reflink a file unless `skip_reflink` is set, and copies it otherwise.
+    pub(crate) fn reflink_or_copy_inner(
+        &self,
+        from: &Path,
+        to: &Path,
+        skip_reflink: bool,
+    ) -> Result<CopyOutcome, ReflinkError> {
+        if self
+            .check_source(from)
+            .map_err(|err| err.with_paths(from, to))?
+        {
+            return self
+                .copy_link(from, to)
+                .map(|()| CopyOutcome::Symlink)
+                .map_err(|err| err.with_paths(from, to));
+        }
+
+        // Unless the target is replaced, an atomic target is created as an unnamed file where
+        // supported, whether it is reflinked or copied
+        let atomic = self.atomic && !self.overwrite;
+        let create = |to: &Path| {
+            let reflink_result = if skip_reflink {
+                None
+            } else if atomic {
+                Some(sys::reflink_atomic(from, to, &self.preserve))
+            } else {
+                Some(sys::reflink(from, to, &self.preserve))
+            };
+            let reflink_error = match reflink_result {
+                None => None,
+                Some(Ok(())) => return Ok(CopyOutcome::Reflinked),
+                Some(Err(err)) => {
+                    match err.reason() {
+                        ReflinkErrorReason::NotFound
+                        | ReflinkErrorReason::SourceNotRegularFile
+                        | ReflinkErrorReason::PermissionDenied
+                        | ReflinkErrorReason::OwnershipNotPermitted
+                        | ReflinkErrorReason::DestinationExists => {
+                            return Err(err);
+                        }
+                        _ => {}
+                    }
+
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(?err, "Failed to reflink, fallback to copy");
+
+                    Some(err.with_paths(from, to))
+                }
+            };
+
+            let stages = if let Some(stages) = &self.fallback {
+                stages.as_slice()
+            } else if fs::metadata(from).is_ok_and(|metadata| sys::is_sparse(&metadata)) {
+                // `fs::copy` allocates the holes of sparse files
+                &[FallbackStage::Sparse, FallbackStage::ReadWrite]
+            } else if atomic || !self.preserve.is_empty() || self.preserve.nofollow {
+                // `fs::copy` only creates named files, gives no access to the target before its
+                // permissions are set, and follows symlinks
+                fallback::DEFAULT_STAGES
+            } else {
+                let bytes = fs::copy(from, to).syscall("copy").map_err(|err| {
+                    // Both regular files and symlinks to regular files can be copied, so unlike
+                    // `reflink` we don't want to report invalid input on both files and symlinks
+                    if from.is_file() {
+                        err
+                    } else {
+                        err.with_reason(ReflinkErrorReason::SourceNotRegularFile)
+                    }
+                })?;
+
+                return Ok(CopyOutcome::Copied {
+                    bytes,
+                    reflink_error,
+                    failed_stages: Vec::new(),
+                });
+            };
+
+            if atomic {
+                sys::copy_atomic(stages, from, to, reflink_error, &self.preserve)
+            } else {
+                fallback::copy(stages, from, to, reflink_error, &self.preserve)
+            }
+        };
+
+        let result = if atomic {
+            create(to)
+        } else {
+            self.create_target(to, create)
+        };
+        result
+            .and_then(|outcome| self.sync_parent(to).map(|()| outcome))
+            .or_else(|err| {
+                if self.is_replaced_by_symlink(&err, from) {
+                    self.copy_link(from, to).map(|()| CopyOutcome::Symlink)
+                } else {
+                    Err(err)
+                }
+            })
+            .map_err(|err| err.with_paths(from, to))
+    }
+
+    /// Applies the symlink policy to the source: returns whether it is a symlink which needs to be
+    /// recreated instead of cloned, or rejects it.
+    ///
+    /// The source may still be replaced by a symlink afterwards. Where supported, it is therefore
+    /// opened without following symlinks, see [`ReflinkOptions::is_replaced_by_symlink`].
+    fn check_source(&self, from: &Path) -> Result<bool, ReflinkError> {
+        if self.symlink_policy == SymlinkPolicy::Follow
+            || !fs::symlink_metadata(from).is_ok_and(|m| m.is_symlink())
+        {
+            return Ok(false);
+        }
+        if self.symlink_policy == SymlinkPolicy::CopyLink {
+            return Ok(true);
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the source path is a symlink",
+        ))
+        .syscall("lstat")
+        .map_err(|err| err.with_reason(ReflinkErrorReason::SourceNotRegularFile))
+    }
+
+    /// Returns whether opening the source failed because it has been replaced by a symlink after
+    /// [`ReflinkOptions::check_source`], and the symlink needs to be recreated instead.
+    ///
+    /// Under [`SymlinkPolicy::Error`], the error of opening the source is returned as is.
+    fn is_replaced_by_symlink(&self, err: &ReflinkError, from: &Path) -> bool {
+        self.symlink_policy == SymlinkPolicy::CopyLink
+            && err.reason() == ReflinkErrorReason::SourceNotRegularFile
+            && fs::symlink_metadata(from).is_ok_and(|m| m.is_symlink())
+    }
+
+    /// Recreates the symlink `from` at `to`.
+    fn copy_link(&self, from: &Path, to: &Path) -> Result<(), ReflinkError> {
+        self.create_target(to, |to| copy_symlink(from, to))
+            .and_then(|()| self.sync_parent(to))
+    }
+
+    /// Flushes the directory entry of the target to disk, if requested.
+    fn sync_parent(&self, to: &Path) -> Result<(), ReflinkError> {
+        if !self.preserve.sync {
+            return Ok(());
+        }
+
+        let dir = match to.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        sys::sync_dir(dir)
+    }
+
+    /// Runs `create` with the path the target should be created at, and moves the result into
+    /// place if the target is being replaced or created atomically.
+    fn create_target<T>(
+        &self,
+        to: &Path,
+        create: impl FnOnce(&Path) -> Result<T, ReflinkError>,
+    ) -> Result<T, ReflinkError> {
+        if self.overwrite || self.atomic {
+            sys::create_via_temporary(to, self.overwrite, create)
+        } else {
+            create(to)
+        }
+    }
+}
+
+/// How [`ReflinkOptions`] handles a source which is a symlink.
+///
+/// The policy only applies to the last component of the source path, symlinks in its parent
+/// directories are always followed. It is applied the same way on every platform.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SymlinkPolicy {
+    /// The file the symlink points to is cloned, or copied.
+    #[default]
+    Follow,
+    /// The symlink itself is recreated at the target, pointing to the same, unmodified target
+    /// path. No data is cloned or copied, and the preserved metadata is not applied to the link.
+    ///
+    /// On Windows, creating symlinks requires either administrator privileges or the developer
+    /// mode to be enabled.
+    CopyLink,
+    /// The operation fails with [`ReflinkErrorReason::SourceNotRegularFile`], like opening the
+    /// source with `O_NOFOLLOW` would.
+    Error,
+}
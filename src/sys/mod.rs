@@ -1,3 +1,11 @@
+use crate::dedupe::DedupeRangeResult;
+use crate::error::Syscall;
+use crate::extents::FileExtent;
+use crate::preserve::Preserve;
+use crate::{
+    fallback, CopyOutcome, FallbackStage, ReflinkError, ReflinkErrorReason, ReflinkSupport,
+    XattrFilter,
+};
 use std::path::Path;
 use std::{fs, io};
 
@@ -5,25 +13,139 @@ use cfg_if::cfg_if;
 
 mod utility;
 
+pub(crate) use utility::{create_via_temporary, AutoRemovedFile};
+
 cfg_if! {
     if #[cfg(unix)] {
         mod unix;
         pub use self::unix::reflink;
+        pub(crate) use self::unix::check_reflink_support;
+        pub(crate) use self::unix::clone_alignment;
+        pub(crate) use self::unix::copy_acls;
+        pub(crate) use self::unix::copy_atomic;
+        pub(crate) use self::unix::copy_file_range;
+        pub(crate) use self::unix::copy_sparse;
+        pub(crate) use self::unix::copy_xattrs;
+        pub(crate) use self::unix::dedupe_range;
+        pub(crate) use self::unix::device_id;
+        pub(crate) use self::unix::error_reason;
+        pub(crate) use self::unix::file_extents;
+        pub(crate) use self::unix::is_sparse;
+        pub(crate) use self::unix::open_source;
+        pub(crate) use self::unix::reflink_atomic;
         pub(crate) use self::unix::reflink_block;
+        pub(crate) use self::unix::reflink_file;
+        pub(crate) use self::unix::reflinkat;
+        pub(crate) use self::unix::same_file;
+        pub(crate) use self::unix::sendfile;
+        pub(crate) use self::unix::sync_dir;
     } else if #[cfg(windows)] {
         mod windows_impl;
         pub use self::windows_impl::reflink;
+        pub(crate) use self::reflink_atomic_via_temporary as reflink_atomic;
         pub use self::windows_impl::check_reflink_support;
+        pub(crate) use self::windows_impl::clone_alignment;
+        pub(crate) use self::copy_acls_skipped as copy_acls;
+        pub(crate) use self::copy_atomic_via_temporary as copy_atomic;
+        pub(crate) use self::copy_file_range_not_supported as copy_file_range;
+        pub(crate) use self::copy_sparse_not_supported as copy_sparse;
+        pub(crate) use self::copy_xattrs_skipped as copy_xattrs;
+        pub(crate) use self::dedupe_range_not_supported as dedupe_range;
+        pub(crate) use self::windows_impl::device_id;
+        pub(crate) use self::windows_impl::error_reason;
+        pub(crate) use self::file_extents_not_supported as file_extents;
+        pub(crate) use self::is_sparse_unknown as is_sparse;
+        pub(crate) use self::open_source_generic as open_source;
         pub(crate) use self::windows_impl::reflink_block;
+        pub(crate) use self::windows_impl::reflink_file;
+        pub(crate) use self::windows_impl::same_file;
+        pub(crate) use self::sendfile_not_supported as sendfile;
+        // directories cannot be flushed without write access to them
+        pub(crate) use self::sync_dir_skipped as sync_dir;
     } else {
         pub use self::reflink_not_supported as reflink;
+        pub(crate) use self::check_reflink_support_unknown as check_reflink_support;
+        pub(crate) use self::clone_alignment_not_supported as clone_alignment;
+        pub(crate) use self::copy_acls_skipped as copy_acls;
+        pub(crate) use self::copy_atomic_via_temporary as copy_atomic;
+        pub(crate) use self::copy_file_range_not_supported as copy_file_range;
+        pub(crate) use self::copy_sparse_not_supported as copy_sparse;
+        pub(crate) use self::copy_xattrs_skipped as copy_xattrs;
+        pub(crate) use self::dedupe_range_not_supported as dedupe_range;
+        pub(crate) use self::device_id_unknown as device_id;
+        pub(crate) use self::error_reason_generic as error_reason;
+        pub(crate) use self::file_extents_not_supported as file_extents;
+        pub(crate) use self::is_sparse_unknown as is_sparse;
+        pub(crate) use self::open_source_generic as open_source;
+        pub(crate) use self::reflink_not_supported as reflink_atomic;
         pub(crate) use self::reflink_block_not_supported as reflink_block;
+        pub(crate) use self::reflink_file_not_supported as reflink_file;
+        pub(crate) use self::same_file_unknown as same_file;
+        pub(crate) use self::sendfile_not_supported as sendfile;
+        pub(crate) use self::sync_dir_skipped as sync_dir;
     }
 }
 
 #[allow(dead_code)]
-pub fn reflink_not_supported(_from: &Path, _to: &Path) -> std::io::Result<()> {
-    Err(std::io::ErrorKind::Unsupported.into())
+pub fn reflink_not_supported(
+    _from: &Path,
+    _to: &Path,
+    _preserve: &Preserve,
+) -> Result<(), ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("reflink")
+}
+
+#[allow(dead_code)]
+pub(crate) fn check_reflink_support_unknown(
+    _from: &Path,
+    _to: &Path,
+) -> io::Result<ReflinkSupport> {
+    Ok(ReflinkSupport::Unknown)
+}
+
+#[allow(dead_code)]
+pub(crate) fn device_id_unknown(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
+
+#[allow(dead_code)]
+pub(crate) fn open_source_generic(
+    from: &Path,
+    _preserve: &Preserve,
+) -> Result<fs::File, ReflinkError> {
+    fs::File::open(from).syscall("open")
+}
+
+#[allow(dead_code)]
+pub(crate) fn same_file_unknown(_a: &fs::File, _b: &fs::File) -> io::Result<bool> {
+    Ok(false)
+}
+
+#[allow(dead_code)]
+pub(crate) fn error_reason_generic(_err: &io::Error) -> Option<ReflinkErrorReason> {
+    None
+}
+
+#[allow(dead_code)]
+pub(crate) fn reflink_atomic_via_temporary(
+    from: &Path,
+    to: &Path,
+    preserve: &Preserve,
+) -> Result<(), ReflinkError> {
+    create_via_temporary(to, false, |temporary| reflink(from, temporary, preserve))
+}
+
+#[allow(dead_code)]
+pub(crate) fn copy_atomic_via_temporary(
+    stages: &[FallbackStage],
+    from: &Path,
+    to: &Path,
+    reflink_error: Option<ReflinkError>,
+    preserve: &Preserve,
+) -> Result<CopyOutcome, ReflinkError> {
+    create_via_temporary(to, false, |temporary| {
+        fallback::copy(stages, from, temporary, reflink_error, preserve)
+    })
 }
 
 #[allow(dead_code)]
@@ -34,6 +156,86 @@ pub(crate) fn reflink_block_not_supported(
     _to_offset: u64,
     _src_length: u64,
     _cluster_size: Option<std::num::NonZeroU64>,
-) -> io::Result<()> {
-    Err(std::io::ErrorKind::Unsupported.into())
+) -> Result<(), ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("reflink_block")
+}
+
+#[allow(dead_code)]
+pub(crate) fn reflink_file_not_supported(
+    _from: &fs::File,
+    _to: &fs::File,
+) -> Result<(), ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("reflink_file")
+}
+
+#[allow(dead_code)]
+pub(crate) fn dedupe_range_not_supported(
+    _from: &fs::File,
+    _from_offset: u64,
+    _src_length: u64,
+    _destinations: &[(&fs::File, u64)],
+) -> Result<Vec<DedupeRangeResult>, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("ioctl_fideduperange")
+}
+
+#[allow(dead_code)]
+pub(crate) fn clone_alignment_not_supported(
+    _file: &fs::File,
+) -> Result<std::num::NonZeroU64, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("fstatfs")
+}
+
+#[allow(dead_code)]
+pub(crate) fn file_extents_not_supported(
+    _file: &fs::File,
+) -> Result<Vec<FileExtent>, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("ioctl_fiemap")
+}
+
+#[allow(dead_code)]
+pub(crate) fn copy_file_range_not_supported(
+    _from: &fs::File,
+    _to: &fs::File,
+) -> Result<u64, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("copy_file_range")
+}
+
+#[allow(dead_code)]
+pub(crate) fn sendfile_not_supported(
+    _from: &fs::File,
+    _to: &fs::File,
+) -> Result<u64, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("sendfile")
+}
+
+#[allow(dead_code)]
+pub(crate) fn copy_sparse_not_supported(
+    _from: &fs::File,
+    _to: &fs::File,
+) -> Result<u64, ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("lseek")
+}
+
+#[allow(dead_code)]
+pub(crate) fn is_sparse_unknown(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+#[allow(dead_code)]
+pub(crate) fn copy_acls_skipped(_from: &fs::File, _to: &fs::File) -> Result<(), ReflinkError> {
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub(crate) fn copy_xattrs_skipped(
+    _from: &fs::File,
+    _to: &fs::File,
+    _filter: &XattrFilter,
+) -> Result<(), ReflinkError> {
+    Ok(())
+}
+
+#[allow(dead_code)]
+pub(crate) fn sync_dir_skipped(_dir: &Path) -> Result<(), ReflinkError> {
+    Ok(())
 }
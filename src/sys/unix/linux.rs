@@ -1,18 +1,481 @@
-use std::convert::TryInto;
-use std::os::unix::io::AsRawFd;
-use std::{fs, io, path::Path};
+use std::convert::TryFrom;
+use std::num::NonZeroU64;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::{fs, io, mem, path::Path};
 
-use crate::sys::utility::AutoRemovedFile;
+use rustix::fs::{AtFlags, Mode, OFlags, SeekFrom, XattrFlags, CWD};
+use rustix::io::Errno;
+use rustix::ioctl::{opcode, Opcode, Updater};
 
-pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
-    let src = fs::File::open(from)?;
-    let permissions = src.metadata()?.permissions();
+use crate::dedupe::{DedupeRangeResult, DedupeStatus};
+use crate::error::Syscall;
+use crate::extents::FileExtent;
+use crate::preserve::Preserve;
+use crate::sys::utility::{create_via_temporary, AutoRemovedFile};
+use crate::{
+    fallback, CopyOutcome, FallbackStage, ReflinkError, ReflinkErrorReason, ReflinkSupport,
+    XattrFilter,
+};
+
+// Magic numbers of `statfs::f_type`, see statfs(2)
+const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+const BCACHEFS_SUPER_MAGIC: u32 = 0xca45_1a4e;
+const OVERLAYFS_SUPER_MAGIC: u32 = 0x794c_7630;
+const EXT4_SUPER_MAGIC: u32 = 0xef53;
+const TMPFS_MAGIC: u32 = 0x0102_1994;
+const RAMFS_MAGIC: u32 = 0x8584_58f6;
+const MSDOS_SUPER_MAGIC: u32 = 0x4d44;
+const EXFAT_SUPER_MAGIC: u32 = 0x2011_bab0;
+const F2FS_SUPER_MAGIC: u32 = 0xf2f5_2010;
+const SQUASHFS_MAGIC: u32 = 0x7371_7368;
+
+// Extended attributes holding POSIX ACLs, see acl(5)
+const POSIX_ACL_ACCESS: &str = "system.posix_acl_access";
+const POSIX_ACL_PREFIX: &[u8] = b"system.posix_acl_";
+
+pub fn reflink(from: &Path, to: &Path, preserve: &Preserve) -> Result<(), ReflinkError> {
+    let src = open_source(from, preserve)?;
+    let metadata = src.metadata().syscall("fstat")?;
 
     // pass O_EXCL to mimic macos behaviour
-    let dest = AutoRemovedFile::create_new(to)?;
-    rustix::fs::ioctl_ficlone(&dest, &src)?;
+    let dest = AutoRemovedFile::create_new(to).syscall("open")?;
+    reflink_file(&src, dest.as_inner_file())?;
+    preserve.finish(&src, &metadata, dest.as_inner_file())?;
+
+    dest.persist();
+    Ok(())
+}
+
+pub fn reflink_atomic(from: &Path, to: &Path, preserve: &Preserve) -> Result<(), ReflinkError> {
+    let src = open_source(from, preserve)?;
+    let metadata = src.metadata().syscall("fstat")?;
+
+    // An unnamed file in the target directory never shows up in the directory listing and
+    // disappears on its own if the process dies before it is linked into place.
+    let dir = match to.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dest = match open_tmpfile(dir) {
+        Ok(dest) => fs::File::from(dest),
+        // The kernel or the file system does not support O_TMPFILE
+        Err(Errno::OPNOTSUPP | Errno::ISDIR | Errno::INVAL) => {
+            return create_via_temporary(to, false, |temporary| reflink(from, temporary, preserve));
+        }
+        Err(err) => return Err(err).syscall("openat"),
+    };
+
+    reflink_file(&src, &dest)?;
+    preserve.finish(&src, &metadata, &dest)?;
+
+    link_tmpfile(&dest, to).syscall("linkat")
+}
+
+/// Copies `from` with `stages` into an unnamed file in the target directory, which is linked into
+/// place afterwards, like [`reflink_atomic`] does for the clone.
+pub(crate) fn copy_atomic(
+    stages: &[FallbackStage],
+    from: &Path,
+    to: &Path,
+    reflink_error: Option<ReflinkError>,
+    preserve: &Preserve,
+) -> Result<CopyOutcome, ReflinkError> {
+    let dir = match to.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut dest = match open_tmpfile(dir) {
+        Ok(dest) => fs::File::from(dest),
+        // The kernel or the file system does not support O_TMPFILE
+        Err(Errno::OPNOTSUPP | Errno::ISDIR | Errno::INVAL) => {
+            return create_via_temporary(to, false, |temporary| {
+                fallback::copy(stages, from, temporary, reflink_error, preserve)
+            });
+        }
+        Err(err) => return Err(err).syscall("openat"),
+    };
+
+    let outcome = fallback::copy_to_file(stages, from, to, &mut dest, reflink_error, preserve)?;
+    link_tmpfile(&dest, to).syscall("linkat")?;
+    Ok(outcome)
+}
+
+/// Opens the source of a reflink or a copy for reading.
+///
+/// Unless symlinks are followed, the source is opened with `O_NOFOLLOW`, so a symlink swapped in
+/// after the symlink policy has been applied is rejected by the kernel instead of being followed.
+pub(crate) fn open_source(from: &Path, preserve: &Preserve) -> Result<fs::File, ReflinkError> {
+    let flags = if preserve.nofollow {
+        OFlags::RDONLY | OFlags::CLOEXEC | OFlags::NOFOLLOW
+    } else {
+        OFlags::RDONLY | OFlags::CLOEXEC
+    };
+    match rustix::fs::open(from, flags, Mode::empty()) {
+        Ok(src) => Ok(src.into()),
+        Err(Errno::LOOP) if preserve.nofollow => Err(Errno::LOOP)
+            .syscall("open")
+            .map_err(|err| err.with_reason(ReflinkErrorReason::SourceNotRegularFile)),
+        Err(err) => Err(err).syscall("open"),
+    }
+}
+
+pub(crate) fn reflink_file(from: &fs::File, to: &fs::File) -> Result<(), ReflinkError> {
+    let ficlone = || {
+        rustix::fs::ioctl_ficlone(to, from)
+            .syscall("ioctl_ficlone")
+            .map_err(ReflinkError::with_clone_reason)
+    };
+
+    match ficlone() {
+        Ok(()) => {}
+        // The kernel refuses to clone a partial block at the end of the source into the middle of
+        // a longer destination
+        Err(err)
+            if Errno::from_io_error(err.io_error()) == Some(Errno::INVAL)
+                && to.metadata().syscall("fstat")?.len() > 0 =>
+        {
+            to.set_len(0).syscall("ftruncate")?;
+            ficlone()?;
+        }
+        Err(err) => return Err(err),
+    }
+
+    // The data of a longer destination past the end of the source is kept otherwise
+    let len = from.metadata().syscall("fstat")?.len();
+    if to.metadata().syscall("fstat")?.len() > len {
+        to.set_len(len).syscall("ftruncate")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn reflinkat(
+    src_dir: BorrowedFd<'_>,
+    src_name: &Path,
+    dst_dir: BorrowedFd<'_>,
+    dst_name: &Path,
+) -> Result<(), ReflinkError> {
+    // O_NONBLOCK keeps a FIFO planted in place of the source from blocking the open, it has no
+    // effect on regular files
+    let src = match rustix::fs::openat(
+        src_dir,
+        src_name,
+        OFlags::RDONLY | OFlags::NOFOLLOW | OFlags::NONBLOCK | OFlags::CLOEXEC,
+        Mode::empty(),
+    ) {
+        Ok(src) => fs::File::from(src),
+        Err(Errno::LOOP) => {
+            return Err(Errno::LOOP)
+                .syscall("openat")
+                .map_err(|err| err.with_reason(ReflinkErrorReason::SourceNotRegularFile));
+        }
+        Err(err) => return Err(err).syscall("openat"),
+    };
+    let metadata = src.metadata().syscall("fstat")?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the source is not a regular file",
+        ))
+        .syscall("fstat")
+        .map_err(|err| err.with_reason(ReflinkErrorReason::SourceNotRegularFile));
+    }
+
+    // pass O_EXCL to mimic macos behaviour, it also refuses to follow a symlink at `dst_name`
+    let dest = rustix::fs::openat(
+        dst_dir,
+        dst_name,
+        OFlags::WRONLY | OFlags::CREATE | OFlags::EXCL | OFlags::NOFOLLOW | OFlags::CLOEXEC,
+        Mode::from_bits_truncate(0o666),
+    )
+    .syscall("openat")?;
+    let dest = fs::File::from(dest);
+
+    let result =
+        reflink_file(&src, &dest).and_then(|()| Preserve::default().finish(&src, &metadata, &dest));
+    if result.is_err() {
+        if let Err(_err) = rustix::fs::unlinkat(dst_dir, dst_name, AtFlags::empty()) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                ?_err,
+                "Failed to remove dest file {} on cleanup (failed to reflink)",
+                dst_name.display(),
+            );
+        }
+    }
+    result
+}
+
+/// Opens an unnamed regular file in `dir`.
+fn open_tmpfile(dir: &Path) -> rustix::io::Result<OwnedFd> {
+    rustix::fs::openat(
+        CWD,
+        dir,
+        OFlags::RDWR | OFlags::TMPFILE | OFlags::CLOEXEC,
+        Mode::from_bits_truncate(0o600),
+    )
+}
+
+/// Gives a name to a file opened with `O_TMPFILE`. Fails if `to` already exists.
+fn link_tmpfile(file: &fs::File, to: &Path) -> io::Result<()> {
+    // Linking through /proc does not require CAP_DAC_READ_SEARCH, unlike AT_EMPTY_PATH, which is
+    // only used if /proc is not mounted.
+    let proc_path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    match rustix::fs::linkat(CWD, proc_path.as_str(), CWD, to, AtFlags::SYMLINK_FOLLOW) {
+        Err(Errno::NOENT) if !Path::new("/proc/self/fd").exists() => {
+            rustix::fs::linkat(file.as_fd(), "", CWD, to, AtFlags::EMPTY_PATH)?;
+            Ok(())
+        }
+        result => Ok(result?),
+    }
+}
+
+/// The largest number of bytes Linux transfers in a single `copy_file_range` or `sendfile` call.
+const MAX_RW_COUNT: usize = 0x7fff_f000;
+
+/// Copies the whole content of `from` into `to` with `copy_file_range`.
+pub(crate) fn copy_file_range(from: &fs::File, to: &fs::File) -> Result<u64, ReflinkError> {
+    let mut off_in = 0;
+    let mut off_out = 0;
+    loop {
+        match rustix::fs::copy_file_range(
+            from,
+            Some(&mut off_in),
+            to,
+            Some(&mut off_out),
+            MAX_RW_COUNT,
+        ) {
+            Ok(0) => return Ok(off_out),
+            Ok(_) | Err(Errno::INTR) => {}
+            Err(err) => return Err(err).syscall("copy_file_range"),
+        }
+    }
+}
+
+/// Copies the whole content of `from` into `to` with `sendfile`, starting at the current position
+/// of `to`.
+pub(crate) fn sendfile(from: &fs::File, to: &fs::File) -> Result<u64, ReflinkError> {
+    let mut offset = 0;
+    loop {
+        match rustix::fs::sendfile(to, from, Some(&mut offset), MAX_RW_COUNT) {
+            Ok(0) => return Ok(offset),
+            Ok(_) | Err(Errno::INTR) => {}
+            Err(err) => return Err(err).syscall("sendfile"),
+        }
+    }
+}
+
+/// Returns whether fewer blocks are allocated for the file than its size requires.
+pub(crate) fn is_sparse(metadata: &fs::Metadata) -> bool {
+    metadata.blocks() * 512 < metadata.len()
+}
+
+/// Copies the data regions of `from` into the empty file `to`, leaving holes where `from` has
+/// holes. Returns the number of bytes copied, not counting the holes.
+pub(crate) fn copy_sparse(from: &fs::File, to: &fs::File) -> Result<u64, ReflinkError> {
+    let len = from.metadata().syscall("fstat")?.len();
+    let mut buffer = vec![0; 128 * 1024];
+    let mut data_bytes = 0;
+    let mut offset = 0;
+    while offset < len {
+        let data_start = match rustix::fs::seek(from, SeekFrom::Data(offset)) {
+            Ok(data_start) => data_start,
+            // Only a hole is left until the end of the file
+            Err(Errno::NXIO) => break,
+            Err(err) => return Err(err).syscall("lseek"),
+        };
+        let data_end = rustix::fs::seek(from, SeekFrom::Hole(data_start))
+            .syscall("lseek")?
+            .min(len);
+
+        offset = data_start;
+        while offset < data_end {
+            let chunk = (data_end - offset).min(buffer.len() as u64) as usize;
+            let read = match from.read_at(&mut buffer[..chunk], offset) {
+                // The file has been truncated concurrently
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err).syscall("pread"),
+            };
+            to.write_all_at(&buffer[..read], offset).syscall("pwrite")?;
+            offset += read as u64;
+            data_bytes += read as u64;
+        }
+        offset = offset.max(data_end);
+    }
+
+    // Writing at an offset leaves holes in between, but the trailing hole needs the size to be set
+    to.set_len(len).syscall("ftruncate")?;
+    Ok(data_bytes)
+}
+
+/// Copies the extended attributes of `from` matched by `filter` to `to`.
+pub(crate) fn copy_xattrs(
+    from: &fs::File,
+    to: &fs::File,
+    filter: &XattrFilter,
+) -> Result<(), ReflinkError> {
+    let names =
+        read_xattr_buffer(|buffer| rustix::fs::flistxattr(from, buffer)).syscall("flistxattr")?;
+
+    for name in names.split(|&byte| byte == 0) {
+        // ACLs are copied by `copy_acls` if requested
+        if name.is_empty() || name.starts_with(POSIX_ACL_PREFIX) || !filter.matches(name) {
+            continue;
+        }
+        let value = match read_xattr_buffer(|buffer| rustix::fs::fgetxattr(from, name, buffer)) {
+            Ok(value) => value,
+            // The attribute has been removed concurrently
+            Err(Errno::NODATA) => continue,
+            Err(err) => return Err(err).syscall("fgetxattr"),
+        };
+        rustix::fs::fsetxattr(to, name, &value, XattrFlags::empty()).syscall("fsetxattr")?;
+    }
+
+    Ok(())
+}
 
-    dest.persist(permissions)
+/// Gives `to` the same access ACL as `from`.
+///
+/// If `from` has no ACL, any ACL `to` inherited from the default ACL of its directory is removed,
+/// so only the mode bits apply to it, like to `from`.
+pub(crate) fn copy_acls(from: &fs::File, to: &fs::File) -> Result<(), ReflinkError> {
+    match read_xattr_buffer(|buffer| rustix::fs::fgetxattr(from, POSIX_ACL_ACCESS, buffer)) {
+        Ok(acl) => rustix::fs::fsetxattr(to, POSIX_ACL_ACCESS, &acl, XattrFlags::empty())
+            .syscall("fsetxattr"),
+        // The source has no ACL, or its file system does not support ACLs
+        Err(Errno::NODATA | Errno::OPNOTSUPP) => {
+            match rustix::fs::fremovexattr(to, POSIX_ACL_ACCESS) {
+                Ok(()) | Err(Errno::NODATA | Errno::OPNOTSUPP) => Ok(()),
+                Err(err) => Err(err).syscall("fremovexattr"),
+            }
+        }
+        Err(err) => Err(err).syscall("fgetxattr"),
+    }
+}
+
+/// Reads a list of extended attributes or the value of one, growing the buffer as needed.
+fn read_xattr_buffer(
+    mut read: impl FnMut(&mut [u8]) -> rustix::io::Result<usize>,
+) -> rustix::io::Result<Vec<u8>> {
+    loop {
+        let mut buffer = vec![0; read(&mut [])?];
+        match read(&mut buffer) {
+            Ok(len) => {
+                buffer.truncate(len);
+                return Ok(buffer);
+            }
+            // The list or the value has grown in between
+            Err(Errno::RANGE) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+pub fn check_reflink_support(from: &Path, to: &Path) -> io::Result<ReflinkSupport> {
+    // Unlike the destination, the source must already exist
+    let from_dev = rustix::fs::stat(from)?.st_dev;
+    let to = existing_ancestor(to)?;
+
+    let from_fs_type = rustix::fs::statfs(from)?.f_type as u32;
+    let to_fs_type = rustix::fs::statfs(to)?.f_type as u32;
+
+    if from_dev != rustix::fs::stat(to)?.st_dev {
+        // Files can only be cloned within a single file system. However, btrfs subvolumes of the
+        // same file system have distinct device ids, and overlayfs reports the device ids of its
+        // layers, so these cases need to be probed.
+        let same_btrfs = from_fs_type == BTRFS_SUPER_MAGIC && to_fs_type == BTRFS_SUPER_MAGIC;
+        let overlay = from_fs_type == OVERLAYFS_SUPER_MAGIC || to_fs_type == OVERLAYFS_SUPER_MAGIC;
+        if !same_btrfs && !overlay {
+            return Ok(ReflinkSupport::NotSupported);
+        }
+    } else {
+        match from_fs_type {
+            BTRFS_SUPER_MAGIC | BCACHEFS_SUPER_MAGIC => return Ok(ReflinkSupport::Supported),
+            EXT4_SUPER_MAGIC | TMPFS_MAGIC | RAMFS_MAGIC | MSDOS_SUPER_MAGIC
+            | EXFAT_SUPER_MAGIC | F2FS_SUPER_MAGIC | SQUASHFS_MAGIC => {
+                return Ok(ReflinkSupport::NotSupported)
+            }
+            // Support of XFS and OCFS2 depends on the options the file system was created with,
+            // the one of NFS on the server and the one of overlayfs on the underlying layers.
+            _ => {}
+        }
+    }
+
+    Ok(probe_reflink_support(from, to))
+}
+
+/// Classifies the errors specific to reflinks.
+pub(crate) fn error_reason(err: &io::Error) -> Option<ReflinkErrorReason> {
+    match Errno::from_io_error(err)? {
+        Errno::XDEV => Some(ReflinkErrorReason::CrossDevice),
+        // Older kernels report ENOTTY if the file system does not implement the ioctl
+        Errno::OPNOTSUPP | Errno::NOTTY => Some(ReflinkErrorReason::NotSupported),
+        _ => None,
+    }
+}
+
+/// Returns `path` if it exists, or its closest existing ancestor otherwise.
+fn existing_ancestor(path: &Path) -> io::Result<&Path> {
+    path.ancestors()
+        .find(|ancestor| ancestor.as_os_str().is_empty() || ancestor.exists())
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .ok_or_else(|| io::ErrorKind::NotFound.into())
+}
+
+/// Clones into an unnamed temporary file, which leaves no trace on the target file system.
+fn probe_reflink_support(from: &Path, to: &Path) -> ReflinkSupport {
+    match probe_reflink(from, to) {
+        Ok(()) => ReflinkSupport::Supported,
+        Err(Errno::OPNOTSUPP | Errno::XDEV | Errno::INVAL | Errno::NOTTY) => {
+            ReflinkSupport::NotSupported
+        }
+        // Probing failed for a reason unrelated to reflink support, e.g. missing permissions or
+        // missing O_TMPFILE support
+        Err(_) => ReflinkSupport::Unknown,
+    }
+}
+
+/// Clones a temporary file of one block next to the source, so the source itself, which may be
+/// large or even a FIFO, is never opened.
+fn probe_reflink(from: &Path, to: &Path) -> rustix::io::Result<()> {
+    let src = open_tmpfile(directory_of(from))?;
+    rustix::io::write(&src, &[0; 4096])?;
+
+    rustix::fs::ioctl_ficlone(open_tmpfile(directory_of(to))?, src)
+}
+
+/// Returns `path` if it is a directory, or the directory containing it otherwise.
+fn directory_of(path: &Path) -> &Path {
+    if path.is_dir() {
+        path
+    } else {
+        match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+}
+
+pub(crate) fn clone_alignment(file: &fs::File) -> Result<NonZeroU64, ReflinkError> {
+    // The fragment size is the fundamental block size, `f_bsize` is only the optimal transfer size
+    let block_size = rustix::fs::fstatfs(file).syscall("fstatfs")?.f_frsize;
+    match u64::try_from(block_size).ok().and_then(NonZeroU64::new) {
+        Some(block_size) => Ok(block_size),
+        None => {
+            let block_size = file.metadata().syscall("fstat")?.blksize();
+            NonZeroU64::new(block_size)
+                .ok_or_else(|| io::Error::other("the file system reports no block size"))
+                .syscall("fstat")
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -23,11 +486,11 @@ pub(crate) fn reflink_block(
     to_offset: u64,
     src_length: u64,
     _cluster_size: Option<std::num::NonZeroU64>,
-) -> io::Result<()> {
+) -> Result<(), ReflinkError> {
     let ret = unsafe {
         libc::ioctl(
             to.as_raw_fd(),
-            libc::FICLONERANGE.try_into().unwrap(),
+            libc::FICLONERANGE as _,
             &libc::file_clone_range {
                 src_fd: from.as_raw_fd().into(),
                 src_offset: from_offset,
@@ -38,7 +501,14 @@ pub(crate) fn reflink_block(
     };
 
     if ret == -1 {
-        Err(io::Error::last_os_error())
+        let err =
+            ReflinkError::new("ioctl_ficlonerange", io::Error::last_os_error()).with_clone_reason();
+        // EINVAL is mostly caused by ranges which are not aligned to the block size
+        if Errno::from_io_error(err.io_error()) == Some(Errno::INVAL) {
+            Err(err.with_reason(ReflinkErrorReason::Misaligned))
+        } else {
+            Err(err)
+        }
     } else {
         Ok(())
     }
@@ -46,3 +516,170 @@ pub(crate) fn reflink_block(
 
 #[cfg(target_os = "android")]
 pub(crate) use crate::sys::reflink_block_not_supported as reflink_block;
+
+// FIDEDUPERANGE and its argument, see ioctl_fideduperange(2)
+const FIDEDUPERANGE: Opcode = opcode::read_write::<FileDedupeRange>(0x94, 54);
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+// The kernel rejects arguments larger than a page
+const MAX_DEDUPE_DESTINATIONS: usize =
+    (4096 - mem::size_of::<FileDedupeRange>()) / mem::size_of::<FileDedupeRangeInfo>();
+
+#[repr(C)]
+struct FileDedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FileDedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+}
+
+#[repr(C)]
+struct FileDedupeRangeArg {
+    range: FileDedupeRange,
+    info: [FileDedupeRangeInfo; MAX_DEDUPE_DESTINATIONS],
+}
+
+pub(crate) fn dedupe_range(
+    from: &fs::File,
+    from_offset: u64,
+    src_length: u64,
+    destinations: &[(&fs::File, u64)],
+) -> Result<Vec<DedupeRangeResult>, ReflinkError> {
+    let mut results = Vec::with_capacity(destinations.len());
+    for destinations in destinations.chunks(MAX_DEDUPE_DESTINATIONS) {
+        let mut arg = FileDedupeRangeArg {
+            range: FileDedupeRange {
+                src_offset: from_offset,
+                src_length,
+                dest_count: destinations.len() as u16,
+                reserved1: 0,
+                reserved2: 0,
+            },
+            info: [FileDedupeRangeInfo::default(); MAX_DEDUPE_DESTINATIONS],
+        };
+        for (info, (to, to_offset)) in arg.info.iter_mut().zip(destinations) {
+            info.dest_fd = to.as_raw_fd().into();
+            info.dest_offset = *to_offset;
+        }
+
+        // SAFETY: `FileDedupeRangeArg` matches `struct file_dedupe_range`, followed by enough
+        // room for `dest_count` entries
+        unsafe {
+            let ioctl = Updater::<FIDEDUPERANGE, _>::new(&mut arg);
+            rustix::ioctl::ioctl(from, ioctl)
+                .syscall("ioctl_fideduperange")
+                .map_err(ReflinkError::with_clone_reason)?;
+        }
+
+        results.extend(arg.info[..destinations.len()].iter().map(|info| {
+            let result = match info.status {
+                FILE_DEDUPE_RANGE_SAME => Ok(DedupeStatus::Same),
+                FILE_DEDUPE_RANGE_DIFFERS => Ok(DedupeStatus::Differs),
+                status => {
+                    let err = ReflinkError::new(
+                        "ioctl_fideduperange",
+                        io::Error::from_raw_os_error(-status),
+                    )
+                    .with_clone_reason();
+                    // EINVAL is mostly caused by ranges which are not aligned to the block size
+                    if -status == Errno::INVAL.raw_os_error() {
+                        Err(err.with_reason(ReflinkErrorReason::Misaligned))
+                    } else {
+                        Err(err)
+                    }
+                }
+            };
+            DedupeRangeResult {
+                result,
+                bytes_deduped: info.bytes_deduped,
+            }
+        }));
+    }
+    Ok(results)
+}
+
+// FS_IOC_FIEMAP and its argument, see
+// https://www.kernel.org/doc/html/latest/filesystems/fiemap.html
+const FS_IOC_FIEMAP: Opcode = opcode::read_write::<Fiemap>(b'f', 11);
+const FIEMAP_FLAG_SYNC: u32 = 0x0000_0001;
+const FIEMAP_MAX_OFFSET: u64 = !0;
+const FIEMAP_EXTENTS_PER_CALL: usize = 128;
+
+#[repr(C)]
+struct Fiemap {
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [u64; 2],
+    fe_flags: u32,
+    fe_reserved: [u32; 3],
+}
+
+#[repr(C)]
+struct FiemapArg {
+    fiemap: Fiemap,
+    extents: [FiemapExtent; FIEMAP_EXTENTS_PER_CALL],
+}
+
+pub(crate) fn file_extents(file: &fs::File) -> Result<Vec<FileExtent>, ReflinkError> {
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut arg = Box::new(FiemapArg {
+            fiemap: Fiemap {
+                fm_start: start,
+                fm_length: FIEMAP_MAX_OFFSET - start,
+                fm_flags: FIEMAP_FLAG_SYNC,
+                fm_mapped_extents: 0,
+                fm_extent_count: FIEMAP_EXTENTS_PER_CALL as u32,
+                fm_reserved: 0,
+            },
+            extents: [FiemapExtent::default(); FIEMAP_EXTENTS_PER_CALL],
+        });
+
+        // SAFETY: `FiemapArg` matches `struct fiemap`, followed by room for `fm_extent_count`
+        // extents
+        unsafe {
+            let ioctl = Updater::<FS_IOC_FIEMAP, _>::new(&mut *arg);
+            rustix::ioctl::ioctl(file, ioctl).syscall("ioctl_fiemap")?;
+        }
+
+        let mapped = &arg.extents[..arg.fiemap.fm_mapped_extents as usize];
+        if mapped.is_empty() {
+            return Ok(extents);
+        }
+        extents.extend(mapped.iter().map(|extent| FileExtent {
+            logical: extent.fe_logical,
+            physical: extent.fe_physical,
+            length: extent.fe_length,
+            flags: extent.fe_flags,
+        }));
+
+        match extents.last() {
+            Some(last) if !last.is_last() => start = last.logical() + last.length(),
+            _ => return Ok(extents),
+        }
+    }
+}
@@ -1,9 +1,16 @@
+use crate::error::Syscall;
+use crate::preserve::{ownership_error, Preserve};
+use crate::{ReflinkError, ReflinkErrorReason};
 use std::{
     ffi::CString,
-    io,
+    fs, io,
     os::{
         raw::{c_char, c_int},
-        unix::ffi::OsStrExt,
+        unix::{
+            ffi::OsStrExt,
+            fs::MetadataExt,
+            io::{AsRawFd, BorrowedFd},
+        },
     },
     path::Path,
 };
@@ -12,25 +19,106 @@ fn cstr(path: &Path) -> io::Result<CString> {
     Ok(CString::new(path.as_os_str().as_bytes())?)
 }
 
-// const CLONE_NOFOLLOW: c_int = 0x0001;
+const CLONE_NOFOLLOW: c_int = 0x0001;
 const CLONE_NOOWNERCOPY: c_int = 0x0002;
 
+// https://github.com/apple/darwin-xnu/blob/0a798f6738bc1db01281fc08ae024145e84df927/bsd/sys/errno.h
+const EPERM: c_int = 1;
+const EXDEV: c_int = 18;
+const ENOTSUP: c_int = 45;
+
 extern "C" {
     // http://www.manpagez.com/man/2/clonefileat/
     // https://github.com/apple/darwin-xnu/blob/0a798f6738bc1db01281fc08ae024145e84df927/bsd/sys/clonefile.h
     // TODO We need weak linkage here (OSX > 10.12, iOS > 10.0), otherwise compilation will fail on older versions
     fn clonefile(src: *const c_char, dest: *const c_char, flags: c_int) -> c_int;
+    fn clonefileat(
+        src_dirfd: c_int,
+        src: *const c_char,
+        dst_dirfd: c_int,
+        dst: *const c_char,
+        flags: c_int,
+    ) -> c_int;
 }
 
-pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
-    let src = cstr(from)?;
-    let dest = cstr(to)?;
+/// `clonefile` copies the timestamps, the extended attributes and the ACLs of the source on its
+/// own.
+pub fn reflink(from: &Path, to: &Path, preserve: &Preserve) -> Result<(), ReflinkError> {
+    let src = cstr(from).syscall("clonefile")?;
+    let dest = cstr(to).syscall("clonefile")?;
 
-    let ret = unsafe { clonefile(src.as_ptr(), dest.as_ptr(), CLONE_NOOWNERCOPY) };
+    let flags = if preserve.ownership {
+        0
+    } else {
+        CLONE_NOOWNERCOPY
+    };
+    let ret = unsafe { clonefile(src.as_ptr(), dest.as_ptr(), flags) };
 
     if ret == -1 {
-        Err(io::Error::last_os_error())
+        return Err(ReflinkError::new("clonefile", io::Error::last_os_error()).with_clone_reason());
+    }
+    if preserve.ownership {
+        check_ownership(from, to)?;
+    }
+    if preserve.sync {
+        fs::File::open(to)
+            .and_then(|clone| clone.sync_all())
+            .syscall("fsync")?;
+    }
+    Ok(())
+}
+
+pub(crate) fn reflinkat(
+    src_dir: BorrowedFd<'_>,
+    src_name: &Path,
+    dst_dir: BorrowedFd<'_>,
+    dst_name: &Path,
+) -> Result<(), ReflinkError> {
+    let src = cstr(src_name).syscall("clonefileat")?;
+    let dest = cstr(dst_name).syscall("clonefileat")?;
+
+    let ret = unsafe {
+        clonefileat(
+            src_dir.as_raw_fd(),
+            src.as_ptr(),
+            dst_dir.as_raw_fd(),
+            dest.as_ptr(),
+            CLONE_NOFOLLOW | CLONE_NOOWNERCOPY,
+        )
+    };
+
+    if ret == -1 {
+        return Err(
+            ReflinkError::new("clonefileat", io::Error::last_os_error()).with_clone_reason()
+        );
+    }
+    Ok(())
+}
+
+/// Fails with `EPERM` and removes the clone if `clonefile` did not copy the owner, which it only
+/// does for privileged callers.
+fn check_ownership(from: &Path, to: &Path) -> Result<(), ReflinkError> {
+    let src = fs::metadata(from).syscall("stat")?;
+    let dest = fs::symlink_metadata(to).syscall("lstat")?;
+    if src.uid() == dest.uid() && src.gid() == dest.gid() {
+        return Ok(());
+    }
+
+    let _ = if dest.is_dir() {
+        fs::remove_dir_all(to)
     } else {
-        Ok(())
+        fs::remove_file(to)
+    };
+    Err(io::Error::from_raw_os_error(EPERM))
+        .syscall("clonefile")
+        .map_err(ownership_error)
+}
+
+/// Classifies the errors specific to reflinks.
+pub(crate) fn error_reason(err: &io::Error) -> Option<ReflinkErrorReason> {
+    match err.raw_os_error()? {
+        EXDEV => Some(ReflinkErrorReason::CrossDevice),
+        ENOTSUP => Some(ReflinkErrorReason::NotSupported),
+        _ => None,
     }
 }
@@ -1,3 +1,10 @@
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::BorrowedFd;
+use std::{fs, io, path::Path};
+
+use crate::error::Syscall;
+use crate::ReflinkError;
+
 use cfg_if::cfg_if;
 
 cfg_if! {
@@ -5,13 +12,92 @@ cfg_if! {
     if #[cfg(all(any(target_os = "linux", target_os = "android"), not(any(target_arch = "sparc", target_arch = "sparc64"))))] {
         mod linux;
         pub use linux::reflink;
+        pub(crate) use linux::check_reflink_support;
+        pub(crate) use linux::clone_alignment;
+        pub(crate) use linux::copy_acls;
+        pub(crate) use linux::copy_atomic;
+        pub(crate) use linux::copy_file_range;
+        pub(crate) use linux::copy_sparse;
+        pub(crate) use linux::copy_xattrs;
+        pub(crate) use linux::dedupe_range;
+        pub(crate) use linux::error_reason;
+        pub(crate) use linux::file_extents;
+        pub(crate) use linux::is_sparse;
+        pub(crate) use linux::open_source;
+        pub(crate) use linux::reflink_atomic;
         pub(crate) use linux::reflink_block;
+        pub(crate) use linux::reflink_file;
+        pub(crate) use linux::reflinkat;
+        pub(crate) use linux::sendfile;
     } else if #[cfg(any(target_os = "macos", target_os = "ios", target_os = "tvos", target_os = "watchos"))] {
         mod macos;
         pub use macos::reflink;
+        pub(crate) use super::check_reflink_support_unknown as check_reflink_support;
+        pub(crate) use super::clone_alignment_not_supported as clone_alignment;
+        // clonefile copies the ACLs and the extended attributes on its own
+        pub(crate) use super::copy_acls_skipped as copy_acls;
+        pub(crate) use super::copy_atomic_via_temporary as copy_atomic;
+        pub(crate) use super::copy_file_range_not_supported as copy_file_range;
+        pub(crate) use super::copy_sparse_not_supported as copy_sparse;
+        pub(crate) use super::copy_xattrs_skipped as copy_xattrs;
+        pub(crate) use super::dedupe_range_not_supported as dedupe_range;
+        pub(crate) use macos::error_reason;
+        pub(crate) use super::file_extents_not_supported as file_extents;
+        pub(crate) use super::is_sparse_unknown as is_sparse;
+        pub(crate) use super::open_source_generic as open_source;
+        // clonefile creates the fully cloned target in a single step
+        pub(crate) use macos::reflink as reflink_atomic;
         pub(crate) use super::reflink_block_not_supported as reflink_block;
+        // fclonefileat only clones into a new file
+        pub(crate) use super::reflink_file_not_supported as reflink_file;
+        pub(crate) use macos::reflinkat;
+        pub(crate) use super::sendfile_not_supported as sendfile;
     } else {
         pub use super::reflink_not_supported as reflink;
+        pub(crate) use super::check_reflink_support_unknown as check_reflink_support;
+        pub(crate) use super::clone_alignment_not_supported as clone_alignment;
+        pub(crate) use super::copy_acls_skipped as copy_acls;
+        pub(crate) use super::copy_atomic_via_temporary as copy_atomic;
+        pub(crate) use super::copy_file_range_not_supported as copy_file_range;
+        pub(crate) use super::copy_sparse_not_supported as copy_sparse;
+        pub(crate) use super::copy_xattrs_skipped as copy_xattrs;
+        pub(crate) use super::dedupe_range_not_supported as dedupe_range;
+        pub(crate) use super::error_reason_generic as error_reason;
+        pub(crate) use super::file_extents_not_supported as file_extents;
+        pub(crate) use super::is_sparse_unknown as is_sparse;
+        pub(crate) use super::open_source_generic as open_source;
+        pub(crate) use super::reflink_not_supported as reflink_atomic;
         pub(crate) use super::reflink_block_not_supported as reflink_block;
+        pub(crate) use super::reflink_file_not_supported as reflink_file;
+        pub(crate) use self::reflinkat_not_supported as reflinkat;
+        pub(crate) use super::sendfile_not_supported as sendfile;
     }
 }
+
+#[allow(dead_code)]
+pub(crate) fn reflinkat_not_supported(
+    _src_dir: BorrowedFd<'_>,
+    _src_name: &Path,
+    _dst_dir: BorrowedFd<'_>,
+    _dst_name: &Path,
+) -> Result<(), ReflinkError> {
+    Err(io::Error::from(io::ErrorKind::Unsupported)).syscall("reflinkat")
+}
+
+/// Flushes the entries of the directory `dir` to disk.
+pub(crate) fn sync_dir(dir: &Path) -> Result<(), ReflinkError> {
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .syscall("fsync")
+}
+
+/// Returns an identifier of the device `path` resides on.
+pub(crate) fn device_id(path: &Path) -> io::Result<Option<u64>> {
+    Ok(Some(fs::metadata(path)?.dev()))
+}
+
+/// Returns `true` if `a` and `b` are open handles of the same file.
+pub(crate) fn same_file(a: &fs::File, b: &fs::File) -> io::Result<bool> {
+    let (a, b) = (a.metadata()?, b.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
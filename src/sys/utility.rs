@@ -1,16 +1,23 @@
 #![allow(dead_code)]
 
 use std::{
-    fs::{remove_file, File, Permissions},
+    ffi::{OsStr, OsString},
+    fs::{self, remove_file, File},
     io,
     path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use crate::error::Syscall;
+use crate::ReflinkError;
+
 #[cfg(unix)]
 use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, RawFd};
 
 #[derive(Debug)]
-pub(super) struct AutoRemovedFile {
+pub(crate) struct AutoRemovedFile {
     // Option<File> uses File's niche, so this is zero cost
     inner: Option<File>,
     path: PathBuf,
@@ -32,13 +39,18 @@ impl AutoRemovedFile {
         self.as_inner_file().as_raw_fd()
     }
 
-    pub fn persist(mut self, perm: Permissions) -> io::Result<()> {
-        self.inner.take().unwrap().set_permissions(perm)
+    /// Keeps the file instead of removing it on drop.
+    pub fn persist(mut self) {
+        self.inner.take();
     }
 
     pub fn as_inner_file(&self) -> &File {
         self.inner.as_ref().unwrap()
     }
+
+    pub fn as_inner_file_mut(&mut self) -> &mut File {
+        self.inner.as_mut().unwrap()
+    }
 }
 
 #[cfg(unix)]
@@ -62,3 +74,63 @@ impl Drop for AutoRemovedFile {
         }
     }
 }
+
+/// Returns a hidden, unique path in the same directory as `path`, suitable for a temporary file
+/// that is later renamed over `path`.
+fn temporary_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.subsec_nanos());
+    let mut file_name = OsString::from(".");
+    file_name.push(path.file_name().unwrap_or_else(|| OsStr::new("reflink")));
+    file_name.push(format!(
+        ".{}-{}-{}.tmp",
+        process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ));
+
+    path.with_file_name(file_name)
+}
+
+/// Runs `create` on a [`temporary_path`] next to `path` and moves the created file to `path`
+/// afterwards. The temporary file is removed if any step fails.
+///
+/// If `replace` is set, the file is renamed over `path`. Otherwise it is hard linked to `path`,
+/// which fails with [`io::ErrorKind::AlreadyExists`] if `path` exists, and then unlinked.
+pub(crate) fn create_via_temporary<T>(
+    path: &Path,
+    replace: bool,
+    create: impl FnOnce(&Path) -> Result<T, ReflinkError>,
+) -> Result<T, ReflinkError> {
+    let temporary = temporary_path(path);
+    let value = match create(&temporary) {
+        Ok(value) => value,
+        // The name of the temporary file is unique to this call, so whatever is found at it has
+        // been created by `create`, even if it fails with `AlreadyExists`
+        Err(err) => {
+            let _ = remove_file(&temporary);
+            return Err(err);
+        }
+    };
+
+    let result = if replace {
+        fs::rename(&temporary, path).syscall("rename")
+    } else {
+        fs::hard_link(&temporary, path).syscall("link")
+    };
+    if result.is_err() || !replace {
+        if let Err(_err) = remove_file(&temporary) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                ?_err,
+                "Failed to remove temporary file {}",
+                temporary.display(),
+            );
+        }
+    }
+
+    result.map(|()| value)
+}
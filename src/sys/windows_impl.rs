@@ -1,11 +1,13 @@
 use super::utility::AutoRemovedFile;
-use crate::ReflinkSupport;
+use crate::error::Syscall;
+use crate::preserve::Preserve;
+use crate::{ReflinkError, ReflinkErrorReason, ReflinkSupport};
 use std::num::NonZeroU64;
 
 use std::{
-    convert::TryInto,
+    convert::{TryFrom, TryInto},
     ffi::c_void,
-    fs::File,
+    fs::{self, File},
     io,
     mem::{self, MaybeUninit},
     os::windows::{ffi::OsStrExt, fs::MetadataExt, io::AsRawHandle},
@@ -14,10 +16,14 @@ use std::{
 
 use windows::core::PCWSTR;
 use windows::Win32::{
-    Foundation::{HANDLE, MAX_PATH},
+    Foundation::{
+        ERROR_INVALID_FUNCTION, ERROR_INVALID_PARAMETER, ERROR_NOT_SAME_DEVICE,
+        ERROR_NOT_SUPPORTED, HANDLE, MAX_PATH,
+    },
     Storage::FileSystem::{
-        GetVolumeInformationByHandleW, GetVolumeInformationW, GetVolumeNameForVolumeMountPointW,
-        GetVolumePathNameW, FILE_ATTRIBUTE_SPARSE_FILE, FILE_FLAGS_AND_ATTRIBUTES,
+        GetFileInformationByHandle, GetVolumeInformationByHandleW, GetVolumeInformationW,
+        GetVolumeNameForVolumeMountPointW, GetVolumePathNameW, BY_HANDLE_FILE_INFORMATION,
+        FILE_ATTRIBUTE_SPARSE_FILE, FILE_FLAGS_AND_ATTRIBUTES,
     },
     System::{
         Ioctl::{
@@ -31,32 +37,94 @@ use windows::Win32::{
     },
 };
 
-pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
-    // Inspired by https://github.com/0xbadfca11/reflink/blob/master/reflink.cpp
-    let src = File::open(from)?;
+pub fn reflink(from: &Path, to: &Path, preserve: &Preserve) -> Result<(), ReflinkError> {
+    let src = File::open(from).syscall("CreateFileW")?;
+    let src_metadata = src.metadata().syscall("GetFileInformationByHandle")?;
+
+    let dest = AutoRemovedFile::create_new(to).syscall("CreateFileW")?;
+    clone_file(&src, &src_metadata, dest.as_inner_file())
+        .map_err(ReflinkError::with_clone_reason)?;
+    preserve.finish(&src, &src_metadata, dest.as_inner_file())?;
+
+    dest.persist();
+    Ok(())
+}
+
+pub(crate) fn reflink_file(from: &File, to: &File) -> Result<(), ReflinkError> {
+    let src_metadata = from.metadata().syscall("GetFileInformationByHandle")?;
+    clone_file(from, &src_metadata, to).map_err(ReflinkError::with_clone_reason)
+}
 
-    let src_metadata = src.metadata()?;
+/// Replaces the content of `dest` with a clone of the whole content of `src`.
+///
+/// If cloning fails, `dest` is restored to its previous length and sparse setting.
+fn clone_file(src: &File, src_metadata: &fs::Metadata, dest: &File) -> Result<(), ReflinkError> {
+    // Inspired by https://github.com/0xbadfca11/reflink/blob/master/reflink.cpp
     let src_file_size = src_metadata.file_size();
-    let src_is_sparse =
-        (FILE_FLAGS_AND_ATTRIBUTES(src_metadata.file_attributes()) & FILE_ATTRIBUTE_SPARSE_FILE).0
-            != 0;
+    let src_is_sparse = is_sparse_file(src_metadata);
+
+    // Queried before `dest` is modified, it fails if the volume does not support block cloning
+    let src_integrity_info = src
+        .get_integrity_information()
+        .syscall("FSCTL_GET_INTEGRITY_INFORMATION")?;
+    let cluster_size: i64 = src_integrity_info.ClusterSizeInBytes.into();
+    if cluster_size != 0 && cluster_size != 4 * 1024 && cluster_size != 64 * 1024 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Cluster size of source must either be 4K or 64K (restricted by ReFS)",
+        ))
+        .syscall("FSCTL_GET_INTEGRITY_INFORMATION");
+    }
 
-    let dest = AutoRemovedFile::create_new(to)?;
+    let dest_metadata = dest.metadata().syscall("GetFileInformationByHandle")?;
+    let dest_file_size = dest_metadata.file_size();
+    let dest_is_sparse = is_sparse_file(&dest_metadata);
 
     // Set the destination to be sparse while we clone.
     // Important to avoid allocating zero-backed real storage when cloning
     // below which will just be released when cloning file extents.
-    dest.set_sparse()?;
+    dest.set_sparse().syscall("FSCTL_SET_SPARSE")?;
 
-    let src_integrity_info = src.get_integrity_information()?;
-    let cluster_size: i64 = src_integrity_info.ClusterSizeInBytes.into();
-    if cluster_size != 0 {
-        if cluster_size != 4 * 1024 && cluster_size != 64 * 1024 {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                "Cluster size of source must either be 4K or 64K (restricted by ReFS)",
-            ));
+    let result = duplicate_file(
+        src,
+        src_file_size,
+        &src_integrity_info,
+        cluster_size,
+        dest,
+        dest_file_size,
+    );
+    if result.is_err() {
+        // Undo the changes made to the destination in preparation of the clone
+        let _ = dest.set_len(dest_file_size);
+        if !dest_is_sparse {
+            let _ = dest.unset_sparse();
         }
+        return result;
+    }
+
+    if !src_is_sparse {
+        dest.unset_sparse().syscall("FSCTL_SET_SPARSE")?;
+    }
+
+    Ok(())
+}
+
+/// Returns `true` if the file described by `metadata` is marked as sparse.
+fn is_sparse_file(metadata: &fs::Metadata) -> bool {
+    (FILE_FLAGS_AND_ATTRIBUTES(metadata.file_attributes()) & FILE_ATTRIBUTE_SPARSE_FILE).0 != 0
+}
+
+/// Clones the whole content of `src` into `dest` of size `dest_file_size`, which is resized to the
+/// size of `src`.
+fn duplicate_file(
+    src: &File,
+    src_file_size: u64,
+    src_integrity_info: &FSCTL_GET_INTEGRITY_INFORMATION_BUFFER,
+    cluster_size: i64,
+    dest: &File,
+    dest_file_size: u64,
+) -> Result<(), ReflinkError> {
+    if cluster_size != 0 {
         // Copy over integrity information. Not sure if this is required.
         let mut dest_integrity_info = FSCTL_SET_INTEGRITY_INFORMATION_BUFFER {
             ChecksumAlgorithm: src_integrity_info.ChecksumAlgorithm,
@@ -79,7 +147,11 @@ pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
     // Later on, we round up the bytes to copy in order to end at a cluster boundary.
     // This might very well result in us cloning past the file end.
     // Let's hope windows api sanitizes this, because otherwise a clean implementation is not really possible.
-    dest.as_inner_file().set_len(src_file_size)?;
+    // A longer destination is only truncated once its data has been replaced.
+    if dest_file_size < src_file_size {
+        dest.set_len(src_file_size)
+            .syscall("SetFileInformationByHandle")?;
+    }
 
     // We must end at a cluster boundary
     let total_copy_len: i64 = {
@@ -96,19 +168,13 @@ pub fn reflink(from: &Path, to: &Path) -> io::Result<()> {
     } else {
         None
     };
-    reflink_block(
-        &src,
-        0,
-        dest.as_inner_file(),
-        0,
-        total_copy_len as u64,
-        cluster_size,
-    )?;
-    if !src_is_sparse {
-        dest.unset_sparse()?;
+    reflink_block(src, 0, dest, 0, total_copy_len as u64, cluster_size)?;
+    if dest_file_size > src_file_size {
+        dest.set_len(src_file_size)
+            .syscall("SetFileInformationByHandle")?;
     }
 
-    dest.persist(src_metadata.permissions())
+    Ok(())
 }
 
 /// Additional functionality for windows files, needed for reflink
@@ -295,6 +361,32 @@ pub fn check_reflink_support(
     }
 }
 
+/// Returns the serial number of the volume `path` resides on.
+pub(crate) fn device_id(path: &Path) -> io::Result<Option<u64>> {
+    let volume_path = get_volume_path(path)?;
+    Ok(Some(get_volume_serial_number(&volume_path)?.into()))
+}
+
+/// Returns `true` if `a` and `b` are open handles of the same file.
+pub(crate) fn same_file(a: &File, b: &File) -> io::Result<bool> {
+    let (a, b) = (get_file_information(a)?, get_file_information(b)?);
+    Ok(a.dwVolumeSerialNumber == b.dwVolumeSerialNumber
+        && a.nFileIndexHigh == b.nFileIndexHigh
+        && a.nFileIndexLow == b.nFileIndexLow)
+}
+
+/// Classifies the windows error codes returned by a failed reflink.
+pub(crate) fn error_reason(err: &io::Error) -> Option<ReflinkErrorReason> {
+    let code = err.raw_os_error()?;
+    if code == ERROR_NOT_SAME_DEVICE.0 as i32 {
+        Some(ReflinkErrorReason::CrossDevice)
+    } else if code == ERROR_INVALID_FUNCTION.0 as i32 || code == ERROR_NOT_SUPPORTED.0 as i32 {
+        Some(ReflinkErrorReason::NotSupported)
+    } else {
+        None
+    }
+}
+
 /// A wrapper function for
 /// [GetVolumePathNameW](https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getvolumepathnamew)
 /// that retrieves the volume mount point where the specified path is mounted.
@@ -353,6 +445,48 @@ fn get_volume_flags(volume_path_w: &[u16]) -> io::Result<u32> {
     Ok(file_system_flags)
 }
 
+/// A wrapper function for
+/// [GetVolumeInformationW](https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getvolumeinformationw)
+/// that returns `VolumeSerialNumber`.
+fn get_volume_serial_number(volume_path_w: &[u16]) -> io::Result<u32> {
+    let mut volume_serial_number = 0u32;
+
+    unsafe {
+        GetVolumeInformationW(
+            PCWSTR(volume_path_w.as_ptr()),
+            None,
+            Some(&mut volume_serial_number as *mut _),
+            None,
+            None,
+            None,
+        )
+    }?;
+
+    Ok(volume_serial_number)
+}
+
+/// A wrapper function for
+/// [GetFileInformationByHandle](https://learn.microsoft.com/en-us/windows/win32/api/fileapi/nf-fileapi-getfileinformationbyhandle).
+fn get_file_information(file: &File) -> io::Result<BY_HANDLE_FILE_INFORMATION> {
+    let mut file_information = BY_HANDLE_FILE_INFORMATION::default();
+
+    unsafe { GetFileInformationByHandle(file.as_handle(), &mut file_information as *mut _) }?;
+
+    Ok(file_information)
+}
+
+pub(crate) fn clone_alignment(file: &File) -> Result<NonZeroU64, ReflinkError> {
+    let integrity_info = file
+        .get_integrity_information()
+        .syscall("FSCTL_GET_INTEGRITY_INFORMATION")?;
+    let cluster_size: i64 = integrity_info.ClusterSizeInBytes.into();
+    u64::try_from(cluster_size)
+        .ok()
+        .and_then(NonZeroU64::new)
+        .ok_or_else(|| io::Error::other("the file system reports no cluster size"))
+        .syscall("FSCTL_GET_INTEGRITY_INFORMATION")
+}
+
 pub(crate) fn reflink_block(
     from: &File,
     from_offset: u64,
@@ -360,7 +494,7 @@ pub(crate) fn reflink_block(
     to_offset: u64,
     src_length: u64,
     cluster_size: Option<NonZeroU64>,
-) -> io::Result<()> {
+) -> Result<(), ReflinkError> {
     const GB: u64 = 1024u64 * 1024 * 1024;
     const MAX_REFS_CLUSTER_SIZE: u64 = 64 * 1024;
 
@@ -384,7 +518,17 @@ pub(crate) fn reflink_block(
             to,
             to_offset + bytes_copied,
             bytes_to_copy,
-        )?;
+        )
+        .map_err(|err| {
+            let err = ReflinkError::new("FSCTL_DUPLICATE_EXTENTS_TO_FILE", err).with_clone_reason();
+            // ERROR_INVALID_PARAMETER is mostly caused by blocks which are not aligned to the
+            // cluster size
+            if err.io_error().raw_os_error() == Some(ERROR_INVALID_PARAMETER.0 as i32) {
+                err.with_reason(ReflinkErrorReason::Misaligned)
+            } else {
+                err
+            }
+        })?;
 
         bytes_copied += bytes_to_copy;
     }
@@ -1,9 +1,18 @@
 use std::fs::{self, File};
 use std::io;
+use std::num::NonZeroU64;
 use std::path::Path;
 use tempfile::tempdir;
 
-use reflink_copy::{reflink, reflink_or_copy};
+#[cfg(unix)]
+use reflink_copy::reflinkat;
+use reflink_copy::{
+    check_reflink_support, clone_alignment, dedupe_files, file_extents, reflink, reflink_dir,
+    reflink_file, reflink_or_copy, reflink_or_copy_outcome, shared_bytes, CloneRangeBuilder,
+    CopyOutcome, DedupeRangeBuilder, DedupeStatus, FallbackStage, RangeAction, RangeSegment,
+    ReflinkBlockBuilder, ReflinkCapabilityCache, ReflinkErrorReason, ReflinkOptions,
+    ReflinkSupport, SymlinkPolicy,
+};
 
 #[test]
 fn reflink_file_does_not_exist() {
@@ -95,6 +104,26 @@ fn reflink_existing_dest_results_in_error() {
     assert_eq!(err.kind(), io::ErrorKind::AlreadyExists)
 }
 
+#[test]
+fn reflink_error_reports_reason_and_paths() {
+    let dir = tempdir().unwrap();
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+
+    let _src_file = File::create(&src_file_path).unwrap();
+    let _dest_file = File::create(&dest_file_path).unwrap();
+
+    let err = reflink(&src_file_path, &dest_file_path).unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.reason(), ReflinkErrorReason::DestinationExists);
+    assert_eq!(err.from(), Some(src_file_path.as_path()));
+    assert_eq!(err.to(), Some(dest_file_path.as_path()));
+    assert!(!err.syscall().is_empty());
+
+    let err: io::Error = err.into();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+}
+
 #[test]
 fn reflink_ok() {
     let dir = tempdir().unwrap();
@@ -148,3 +177,1043 @@ fn reflink_or_copy_ok() {
         out.metadata().unwrap().permissions()
     );
 }
+
+#[test]
+fn reflink_dir_src_is_file() {
+    let dir = tempdir().unwrap();
+    let src_file_path = dir.path().join("src.txt");
+    let dest_dir_path = dir.path().join("dest");
+
+    fs::write(&src_file_path, b"this is a test").unwrap();
+
+    let err = reflink_dir(&src_file_path, &dest_dir_path).unwrap_err();
+    println!("{:?}", err);
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!dest_dir_path.exists());
+}
+
+#[test]
+fn reflink_dir_existing_dest_results_in_error() {
+    let dir = tempdir().unwrap();
+    let src_dir_path = dir.path().join("src");
+    let dest_dir_path = dir.path().join("dest");
+
+    fs::create_dir(&src_dir_path).unwrap();
+    fs::create_dir(&dest_dir_path).unwrap();
+
+    let err = reflink_dir(&src_dir_path, &dest_dir_path).unwrap_err();
+    println!("{:?}", err);
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(err.from(), Some(src_dir_path.as_path()));
+    assert_eq!(err.to(), Some(dest_dir_path.as_path()));
+}
+
+#[test]
+fn reflink_dir_dest_inside_src_results_in_error() {
+    let dir = tempdir().unwrap();
+    let src_dir_path = dir.path().join("src");
+
+    fs::create_dir(&src_dir_path).unwrap();
+    fs::write(src_dir_path.join("top.txt"), b"top").unwrap();
+
+    for dest_dir_path in [src_dir_path.join("dest"), src_dir_path.join("../src/dest")] {
+        let err = reflink_dir(&src_dir_path, &dest_dir_path).unwrap_err();
+        println!("{:?}", err);
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!dest_dir_path.exists());
+    }
+}
+
+#[test]
+fn reflink_dir_recreates_tree() {
+    let dir = tempdir().unwrap();
+    let src_dir_path = dir.path().join("src");
+    let dest_dir_path = dir.path().join("dest");
+
+    fs::create_dir_all(src_dir_path.join("a/b")).unwrap();
+    fs::write(src_dir_path.join("top.txt"), b"top").unwrap();
+    fs::write(src_dir_path.join("a/b/nested.txt"), b"nested").unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("a/b/nested.txt", src_dir_path.join("link")).unwrap();
+
+    let entries = reflink_dir(&src_dir_path, &dest_dir_path).unwrap();
+    println!("{:?}", entries);
+    assert_eq!(entries.len(), if cfg!(unix) { 5 } else { 4 });
+
+    assert!(dest_dir_path.join("a/b").is_dir());
+    #[cfg(unix)]
+    assert_eq!(
+        fs::read_link(dest_dir_path.join("link")).unwrap(),
+        Path::new("a/b/nested.txt")
+    );
+
+    for entry in &entries {
+        if entry.file_type().is_dir() || entry.file_type().is_symlink() {
+            assert!(entry.error().is_none());
+        } else if entry.error().is_none() {
+            // do not panic on failed clones, CI envs are old and will probably error out
+            assert_eq!(
+                fs::read(entry.to()).unwrap(),
+                fs::read(entry.from()).unwrap()
+            );
+        }
+    }
+}
+
+#[test]
+fn reflink_overwrite_existing_dest() {
+    let dir = tempdir().unwrap();
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+
+    fs::write(&src_file_path, b"this is a test").unwrap();
+    fs::write(&dest_file_path, b"old content").unwrap();
+
+    let res = ReflinkOptions::new()
+        .overwrite(true)
+        .reflink(&src_file_path, &dest_file_path);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if res.is_ok() {
+        assert_eq!(fs::read(&dest_file_path).unwrap(), b"this is a test");
+    } else {
+        assert_eq!(fs::read(&dest_file_path).unwrap(), b"old content");
+    }
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn reflink_or_copy_overwrite_existing_dest() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    fs::write(&out, b"old content").unwrap();
+
+    let err = reflink_or_copy(&input, &out).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    ReflinkOptions::new()
+        .overwrite(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+    assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn reflink_atomic_ok() {
+    let dir = tempdir().unwrap();
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("dest.txt");
+
+    fs::write(&src_file_path, b"this is a test").unwrap();
+
+    let res = ReflinkOptions::new()
+        .atomic(true)
+        .reflink(&src_file_path, &dest_file_path);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if res.is_ok() {
+        assert_eq!(fs::read(&dest_file_path).unwrap(), b"this is a test");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    } else {
+        assert!(!dest_file_path.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
+
+#[test]
+fn reflink_or_copy_atomic_existing_dest_results_in_error() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    fs::write(&out, b"old content").unwrap();
+
+    let err = ReflinkOptions::new()
+        .atomic(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+    assert_eq!(fs::read(&out).unwrap(), b"old content");
+    assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn reflink_or_copy_atomic_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    ReflinkOptions::new()
+        .atomic(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+    assert_eq!(fs::read_dir(tmpdir.path()).unwrap().count(), 2);
+}
+
+#[test]
+fn reflink_or_copy_outcome_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    let outcome = reflink_or_copy_outcome(&input, &out).unwrap();
+    println!("{:?}", outcome);
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+    match outcome {
+        CopyOutcome::Reflinked => assert!(outcome.reflink_error().is_none()),
+        _ => {
+            assert_eq!(outcome.bytes_written(), Some(5));
+            assert!(outcome.reflink_error().is_some());
+        }
+    }
+}
+
+#[test]
+fn reflink_or_copy_fallback_ladder_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    for (i, stage) in [
+        FallbackStage::CopyFileRange,
+        FallbackStage::Sendfile,
+        FallbackStage::ReadWrite,
+    ]
+    .iter()
+    .enumerate()
+    {
+        let out = tmpdir.path().join(format!("out{}.txt", i));
+        let outcome = ReflinkOptions::new()
+            .fallback(&[*stage, FallbackStage::ReadWrite])
+            .reflink_or_copy_outcome(&input, &out)
+            .unwrap();
+        println!("{:?}", outcome);
+        assert_eq!(fs::read(&out).unwrap(), b"hello");
+        if !outcome.is_reflinked() {
+            assert_eq!(outcome.bytes_written(), Some(5));
+        }
+    }
+}
+
+#[test]
+fn reflink_or_copy_empty_fallback_ladder() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    let result = ReflinkOptions::new()
+        .fallback(&[])
+        .reflink_or_copy(&input, &out);
+    println!("{:?}", result);
+    // do not panic for now, CI envs are old and will probably error out
+    match result {
+        Ok(written) => assert_eq!(written, None),
+        Err(_) => assert!(!out.exists()),
+    }
+}
+
+#[test]
+fn reflink_or_copy_sparse_ok() {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.img");
+    let out = tmpdir.path().join("out.img");
+
+    let mut file = File::create(&input).unwrap();
+    file.set_len(16 * 1024 * 1024).unwrap();
+    file.seek(SeekFrom::Start(8 * 1024 * 1024)).unwrap();
+    file.write_all(b"hello").unwrap();
+    drop(file);
+
+    let outcome = ReflinkOptions::new()
+        .fallback(&[FallbackStage::Sparse, FallbackStage::ReadWrite])
+        .reflink_or_copy_outcome(&input, &out)
+        .unwrap();
+    println!("{:?}", outcome);
+    assert_eq!(fs::read(&out).unwrap(), fs::read(&input).unwrap());
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let CopyOutcome::Sparse {
+        bytes, data_bytes, ..
+    } = outcome
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        assert_eq!(bytes, 16 * 1024 * 1024);
+        assert!(data_bytes < 16 * 1024 * 1024);
+        assert!(fs::metadata(&out).unwrap().blocks() * 512 < 16 * 1024 * 1024);
+    }
+
+    // sparse files are detected without an explicit fallback ladder as well
+    let out = tmpdir.path().join("out2.img");
+    let outcome = reflink_or_copy_outcome(&input, &out).unwrap();
+    println!("{:?}", outcome);
+    assert_eq!(fs::read(&out).unwrap(), fs::read(&input).unwrap());
+}
+
+#[test]
+fn reflink_or_copy_sparse_returns_length() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.img");
+    let out = tmpdir.path().join("out.img");
+
+    // A file made of a single hole
+    File::create(&input).unwrap().set_len(1024 * 1024).unwrap();
+
+    let res = reflink_or_copy(&input, &out).unwrap();
+    println!("{:?}", res);
+    // Like `fs::copy`, the holes are counted as well
+    if let Some(bytes) = res {
+        assert_eq!(bytes, 1024 * 1024);
+    }
+    assert_eq!(fs::metadata(&out).unwrap().len(), 1024 * 1024);
+}
+
+#[test]
+fn reflink_or_copy_preserve_timestamps() {
+    use std::fs::FileTimes;
+    use std::time::{Duration, SystemTime};
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    let accessed = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_100_000_000);
+    File::options()
+        .write(true)
+        .open(&input)
+        .unwrap()
+        .set_times(
+            FileTimes::new()
+                .set_accessed(accessed)
+                .set_modified(modified),
+        )
+        .unwrap();
+
+    let outcome = ReflinkOptions::new()
+        .preserve_timestamps(true)
+        .reflink_or_copy_outcome(&input, &out)
+        .unwrap();
+    println!("{:?}", outcome);
+    // Reading the target updates its access time, so it is checked first
+    let metadata = fs::metadata(&out).unwrap();
+    assert_eq!(metadata.accessed().unwrap(), accessed);
+    assert_eq!(metadata.modified().unwrap(), modified);
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_or_copy_preserve_xattrs() {
+    use reflink_copy::XattrFilter;
+    use rustix::fs::{getxattr, setxattr, XattrFlags};
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    // do not panic for now, CI envs are old and will probably error out
+    if let Err(err) = setxattr(&input, "user.reflink_copy", b"test", XattrFlags::empty()) {
+        println!("user xattrs are not supported: {:?}", err);
+        return;
+    }
+
+    let read = |path: &Path| {
+        let mut buffer = [0; 16];
+        getxattr(path, "user.reflink_copy", &mut buffer[..]).map(|len| buffer[..len].to_vec())
+    };
+
+    let out = tmpdir.path().join("out1.txt");
+    ReflinkOptions::new()
+        .preserve_xattrs(Some(XattrFilter::all().include_namespace("user")))
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+    assert_eq!(read(&out).unwrap(), b"test");
+
+    let out = tmpdir.path().join("out2.txt");
+    ReflinkOptions::new()
+        .preserve_xattrs(Some(XattrFilter::all().exclude_namespace("user")))
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+    assert!(read(&out).is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflink_or_copy_preserve_acls() {
+    use rustix::fs::{getxattr, setxattr, XattrFlags};
+
+    // An ACL granting read access to uid 1000 in the format of acl(5)
+    let mut acl = 2u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in [
+        (0x01u16, 6u16, u32::MAX),
+        (0x02, 4, 1000),
+        (0x04, 4, u32::MAX),
+        (0x10, 4, u32::MAX),
+        (0x20, 4, u32::MAX),
+    ] {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    let has_acl = |path: &Path| {
+        let mut buffer = [0; 64];
+        getxattr(path, "system.posix_acl_access", &mut buffer[..]).is_ok()
+    };
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    fs::write(&input, b"hello").unwrap();
+    // do not panic for now, CI envs are old and will probably error out
+    if let Err(err) = setxattr(&input, "system.posix_acl_access", &acl, XattrFlags::empty()) {
+        println!("ACLs are not supported: {:?}", err);
+        return;
+    }
+
+    let out = tmpdir.path().join("out.txt");
+    ReflinkOptions::new()
+        .preserve_acls(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+    assert!(has_acl(&out));
+
+    // A source without ACL does not keep the ACL inherited from the target directory
+    let plain = tmpdir.path().join("plain.txt");
+    fs::write(&plain, b"hello").unwrap();
+    let dir = tmpdir.path().join("dir");
+    fs::create_dir(&dir).unwrap();
+    setxattr(&dir, "system.posix_acl_default", &acl, XattrFlags::empty()).unwrap();
+
+    let out = dir.join("inherited.txt");
+    reflink_or_copy(&plain, &out).unwrap();
+    assert!(has_acl(&out));
+
+    let out = dir.join("preserved.txt");
+    ReflinkOptions::new()
+        .preserve_acls(true)
+        .reflink_or_copy(&plain, &out)
+        .unwrap();
+    assert!(!has_acl(&out));
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_or_copy_preserve_ownership() {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    // do not panic for now, CI envs are old and will probably error out
+    if let Err(err) = chown(&input, Some(1000), Some(1000)) {
+        println!("not running privileged: {:?}", err);
+        return;
+    }
+
+    ReflinkOptions::new()
+        .preserve_ownership(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+    let metadata = fs::metadata(&out).unwrap();
+    assert_eq!((metadata.uid(), metadata.gid()), (1000, 1000));
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_or_copy_follow_symlinks() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let link = tmpdir.path().join("link.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    std::os::unix::fs::symlink(&input, &link).unwrap();
+
+    let out = tmpdir.path().join("out1.txt");
+    reflink_or_copy(&link, &out).unwrap();
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+
+    let out = tmpdir.path().join("out2.txt");
+    let err = ReflinkOptions::new()
+        .follow_symlinks(false)
+        .reflink_or_copy(&link, &out)
+        .unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.reason(), ReflinkErrorReason::SourceNotRegularFile);
+    assert!(!out.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn reflink_symlink_policy_copy_link() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let link = tmpdir.path().join("link.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    std::os::unix::fs::symlink("in.txt", &link).unwrap();
+
+    let mut options = ReflinkOptions::new();
+    options.symlink_policy(SymlinkPolicy::CopyLink);
+
+    let out = tmpdir.path().join("out1.txt");
+    options.reflink(&link, &out).unwrap();
+    assert_eq!(fs::read_link(&out).unwrap(), Path::new("in.txt"));
+
+    let out = tmpdir.path().join("out2.txt");
+    let outcome = options
+        .atomic(true)
+        .reflink_or_copy_outcome(&link, &out)
+        .unwrap();
+    assert!(matches!(outcome, CopyOutcome::Symlink));
+    assert_eq!(fs::read_link(&out).unwrap(), Path::new("in.txt"));
+
+    // Regular files are unaffected by the policy
+    let out = tmpdir.path().join("out3.txt");
+    options.reflink_or_copy(&input, &out).unwrap();
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+    assert!(!fs::symlink_metadata(&out).unwrap().is_symlink());
+}
+
+#[test]
+fn reflink_or_copy_sync_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    ReflinkOptions::new()
+        .atomic(true)
+        .preserve_all(true)
+        .preserve_ownership(false)
+        .sync(true)
+        .reflink_or_copy(&input, &out)
+        .unwrap();
+    assert_eq!(fs::read(&out).unwrap(), b"hello");
+}
+
+#[test]
+fn reflink_file_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    fs::write(&out, b"previous content").unwrap();
+
+    let from = File::open(&input).unwrap();
+    let to = File::options().write(true).open(&out).unwrap();
+    match reflink_file(&from, &to) {
+        Ok(()) => assert_eq!(fs::read(&out).unwrap(), b"hello"),
+        Err(err) => {
+            // do not panic for now, CI envs are old and will probably error out
+            eprintln!("{err}");
+            assert_eq!(err.reason(), ReflinkErrorReason::NotSupported);
+            assert_eq!(err.from(), None);
+            assert_eq!(err.to(), None);
+            // a failed clone must not destroy the destination
+            assert_eq!(fs::read(&out).unwrap(), b"previous content");
+        }
+    }
+}
+
+#[test]
+fn reflink_sync_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+    let out = tmpdir.path().join("out.txt");
+
+    fs::write(&input, b"hello").unwrap();
+    fs::write(&out, b"previous content").unwrap();
+
+    let res = ReflinkOptions::new()
+        .overwrite(true)
+        .sync(true)
+        .reflink(&input, &out);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if res.is_ok() {
+        assert_eq!(fs::read(&out).unwrap(), b"hello");
+    } else {
+        assert_eq!(fs::read(&out).unwrap(), b"previous content");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn reflinkat_ok() {
+    let tmpdir = tempdir().unwrap();
+    let dir = File::open(tmpdir.path()).unwrap();
+
+    fs::write(tmpdir.path().join("in.txt"), b"hello").unwrap();
+
+    match reflinkat(&dir, "in.txt", &dir, "out.txt") {
+        Ok(()) => assert_eq!(fs::read(tmpdir.path().join("out.txt")).unwrap(), b"hello"),
+        Err(err) => {
+            // do not panic for now, CI envs are old and will probably error out
+            println!("{err}");
+            assert_eq!(err.from(), Some(Path::new("in.txt")));
+            assert!(!tmpdir.path().join("out.txt").exists());
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn reflinkat_does_not_follow_symlinks() {
+    let tmpdir = tempdir().unwrap();
+    let dir = File::open(tmpdir.path()).unwrap();
+
+    fs::write(tmpdir.path().join("in.txt"), b"hello").unwrap();
+    std::os::unix::fs::symlink("in.txt", tmpdir.path().join("link")).unwrap();
+
+    let err = reflinkat(&dir, "link", &dir, "out.txt").unwrap_err();
+    println!("{err}");
+    assert_eq!(err.reason(), ReflinkErrorReason::SourceNotRegularFile);
+    assert!(!tmpdir.path().join("out.txt").exists());
+
+    std::os::unix::fs::symlink("out.txt", tmpdir.path().join("dangling")).unwrap();
+    let err = reflinkat(&dir, "in.txt", &dir, "dangling").unwrap_err();
+    println!("{err}");
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert!(!tmpdir.path().join("out.txt").exists());
+}
+
+#[test]
+fn dedupe_range_ok() {
+    let tmpdir = tempdir().unwrap();
+    let original = tmpdir.path().join("original.bin");
+    let same = tmpdir.path().join("same.bin");
+    let differs = tmpdir.path().join("differs.bin");
+
+    fs::write(&original, [1; 8192]).unwrap();
+    fs::write(&same, [1; 8192]).unwrap();
+    fs::write(&differs, [2; 8192]).unwrap();
+
+    let from = File::open(&original).unwrap();
+    let same_file = File::options().write(true).open(&same).unwrap();
+    let differs_file = File::options().write(true).open(&differs).unwrap();
+    let res = DedupeRangeBuilder::new(&from, NonZeroU64::new(8192).unwrap())
+        .destination(&same_file, 0)
+        .destination(&differs_file, 0)
+        .dedupe();
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Ok(results) = res {
+        assert_eq!(results.len(), 2);
+        if results[0].error().is_none() {
+            assert_eq!(results[0].status(), Some(DedupeStatus::Same));
+        }
+        if results[1].error().is_none() {
+            assert_eq!(results[1].status(), Some(DedupeStatus::Differs));
+            assert_eq!(results[1].bytes_deduped(), 0);
+        }
+    }
+
+    assert_eq!(fs::read(&same).unwrap(), [1; 8192]);
+    assert_eq!(fs::read(&differs).unwrap(), [2; 8192]);
+}
+
+#[test]
+fn dedupe_files_ok() {
+    let tmpdir = tempdir().unwrap();
+    let a = tmpdir.path().join("a.bin");
+    let b = tmpdir.path().join("b.bin");
+
+    // not a multiple of the block size, to cover the unaligned tail
+    fs::write(&a, [1; 10000]).unwrap();
+    fs::write(&b, [1; 10000]).unwrap();
+
+    let res = dedupe_files(&a, &b);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Ok(shared) = res {
+        assert!(shared <= 10000);
+    }
+    assert_eq!(fs::read(&b).unwrap(), [1; 10000]);
+}
+
+#[test]
+fn dedupe_files_size_mismatch() {
+    let tmpdir = tempdir().unwrap();
+    let a = tmpdir.path().join("a.bin");
+    let b = tmpdir.path().join("b.bin");
+
+    fs::write(&a, [1; 8192]).unwrap();
+    fs::write(&b, [1; 4096]).unwrap();
+
+    let err = dedupe_files(&a, &b).unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(err.from(), Some(a.as_path()));
+    assert_eq!(err.to(), Some(b.as_path()));
+}
+
+#[test]
+fn file_extents_ok() {
+    use std::io::{Seek, Write};
+
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+    let out = tmpdir.path().join("out.bin");
+
+    let mut file = File::create(&input).unwrap();
+    file.write_all(&[1; 4096]).unwrap();
+    file.seek(io::SeekFrom::Start(1024 * 1024)).unwrap();
+    file.write_all(&[2; 4096]).unwrap();
+    drop(file);
+
+    let res = file_extents(&File::open(&input).unwrap());
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Ok(extents) = res {
+        assert!(!extents.is_empty());
+        assert!(extents.last().unwrap().is_last());
+        assert!(extents
+            .windows(2)
+            .all(|pair| pair[0].logical() + pair[0].length() <= pair[1].logical()));
+        let covers = |offset| {
+            extents.iter().any(|extent| {
+                (extent.logical()..extent.logical() + extent.length()).contains(&offset)
+            })
+        };
+        assert!(covers(0));
+        assert!(covers(1024 * 1024));
+    }
+
+    let outcome = reflink_or_copy_outcome(&input, &out).unwrap();
+    if let Ok(extents) = file_extents(&File::open(&out).unwrap()) {
+        if outcome.is_reflinked() {
+            assert!(extents.iter().all(|extent| extent.is_shared()));
+        }
+    }
+}
+
+#[test]
+fn shared_bytes_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+    let out = tmpdir.path().join("out.bin");
+
+    fs::write(&input, [1; 16384]).unwrap();
+    let outcome = reflink_or_copy_outcome(&input, &out).unwrap();
+
+    let res = shared_bytes(&input, &input);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Ok(bytes) = res {
+        assert_eq!(bytes.shared(), bytes.total());
+        assert_eq!(bytes.total(), 16384);
+    }
+
+    let res = shared_bytes(&input, &out);
+    println!("{:?}", res);
+    if let Ok(bytes) = res {
+        assert_eq!(bytes.total(), 16384);
+        if outcome.is_reflinked() {
+            assert_eq!(bytes.shared(), 16384);
+        } else {
+            assert_eq!(bytes.distinct(), 16384);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn shared_bytes_cross_device() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+
+    fs::write(&input, [1; 16384]).unwrap();
+
+    // procfs is a file system of its own
+    let err = shared_bytes(&input, "/proc/self/status").unwrap_err();
+    println!("{:?}", err);
+    assert_eq!(err.reason(), ReflinkErrorReason::CrossDevice);
+}
+
+#[test]
+fn clone_alignment_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+    let out = tmpdir.path().join("out.bin");
+
+    fs::write(&input, [1; 8192]).unwrap();
+    let from = File::open(&input).unwrap();
+
+    let res = clone_alignment(&from);
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Ok(cluster_size) = res {
+        assert!(cluster_size.get().is_power_of_two());
+
+        let to = File::create(&out).unwrap();
+        to.set_len(8192).unwrap();
+        let res = ReflinkBlockBuilder::new(&from, &to, NonZeroU64::new(8192).unwrap())
+            .auto_cluster_size(true)
+            .reflink_block();
+        println!("{:?}", res);
+        if res.is_ok() {
+            assert_eq!(fs::read(&out).unwrap(), [1; 8192]);
+        }
+    }
+}
+
+#[test]
+fn clone_range_plan() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+    let out = tmpdir.path().join("out.bin");
+
+    let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    fs::write(&input, &data).unwrap();
+    let from = File::open(&input).unwrap();
+    let to = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&out)
+        .unwrap();
+    let cluster_size = NonZeroU64::new(4096).unwrap();
+    let summary = |segments: Vec<RangeSegment>| {
+        segments
+            .iter()
+            .map(|s| (s.action(), s.from_offset(), s.to_offset(), s.length()))
+            .collect::<Vec<_>>()
+    };
+
+    // The range ends in the middle of the source file, so the tail is copied
+    let plan = CloneRangeBuilder::new(&from, &to, 5000)
+        .cluster_size(cluster_size)
+        .plan()
+        .unwrap();
+    assert_eq!(
+        summary(plan),
+        [
+            (RangeAction::Clone, 0, 0, 4096),
+            (RangeAction::Copy, 4096, 4096, 904)
+        ]
+    );
+
+    // The offsets are not aligned to each other, so nothing can be cloned
+    let plan = CloneRangeBuilder::new(&from, &to, 1000)
+        .from_offset(100)
+        .to_offset(200)
+        .cluster_size(cluster_size)
+        .plan()
+        .unwrap();
+    assert_eq!(summary(plan), [(RangeAction::Copy, 100, 200, 1000)]);
+
+    // The range ends at the end of both files, so the tail is cloned
+    let plan = CloneRangeBuilder::new(&from, &to, 9900)
+        .from_offset(100)
+        .to_offset(100)
+        .cluster_size(cluster_size)
+        .plan()
+        .unwrap();
+    let tail = if cfg!(windows) { 8192 } else { 5904 };
+    assert_eq!(
+        summary(plan),
+        [
+            (RangeAction::Copy, 100, 100, 3996),
+            (RangeAction::Clone, 4096, 4096, tail)
+        ]
+    );
+
+    // An empty range does not extend the destination
+    let segments = CloneRangeBuilder::new(&from, &to, 0)
+        .to_offset(100)
+        .clone_range()
+        .unwrap();
+    assert!(segments.is_empty());
+    assert_eq!(to.metadata().unwrap().len(), 0);
+
+    let err = CloneRangeBuilder::new(&from, &to, u64::MAX)
+        .to_offset(100)
+        .cluster_size(cluster_size)
+        .clone_range()
+        .unwrap_err();
+    println!("{}", err);
+    assert_eq!(err.reason(), ReflinkErrorReason::InvalidRange);
+    assert_eq!(err.syscall(), "");
+
+    let res = CloneRangeBuilder::new(&from, &to, 9900)
+        .from_offset(100)
+        .to_offset(100)
+        .clone_range();
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if res.is_ok() {
+        assert_eq!(fs::read(&out).unwrap()[100..], data[100..]);
+    }
+}
+
+#[test]
+fn reflink_block_validate() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.bin");
+    let out = tmpdir.path().join("out.bin");
+
+    fs::write(&input, [1; 16384]).unwrap();
+    let from = File::options().read(true).write(true).open(&input).unwrap();
+    let to = File::create(&out).unwrap();
+    to.set_len(16384).unwrap();
+    let cluster_size = NonZeroU64::new(4096).unwrap();
+    let builder = |to, length| {
+        ReflinkBlockBuilder::new(&from, to, NonZeroU64::new(length).unwrap())
+            .cluster_size(cluster_size)
+            .validate(true)
+    };
+
+    let err = builder(&to, 4096)
+        .from_offset(100)
+        .reflink_block()
+        .unwrap_err();
+    assert_eq!(err.reason(), ReflinkErrorReason::Misaligned);
+    assert_eq!(err.syscall(), "");
+
+    let err = builder(&to, 4096)
+        .to_offset(u64::MAX - 4095)
+        .reflink_block()
+        .unwrap_err();
+    assert_eq!(err.reason(), ReflinkErrorReason::InvalidRange);
+
+    let err = builder(&from, 8192)
+        .to_offset(4096)
+        .reflink_block()
+        .unwrap_err();
+    assert_eq!(err.reason(), ReflinkErrorReason::InvalidRange);
+
+    let res = builder(&to, 8192).to_offset(4096).reflink_block();
+    println!("{:?}", res);
+    // do not panic for now, CI envs are old and will probably error out
+    if let Err(err) = res {
+        assert_ne!(err.reason(), ReflinkErrorReason::InvalidRange);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn check_reflink_support_matches_reflink() {
+    let dir = tempdir().unwrap();
+    let src_file_path = dir.path().join("src.txt");
+    let dest_file_path = dir.path().join("subdir/dest.txt");
+
+    fs::write(&src_file_path, b"this is a test").unwrap();
+
+    let support = check_reflink_support(&src_file_path, &dest_file_path).unwrap();
+    println!("{:?}", support);
+    assert_eq!(
+        check_reflink_support(dir.path(), dir.path()).unwrap(),
+        support
+    );
+    // the probe must not leave anything behind
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    fs::create_dir(dir.path().join("subdir")).unwrap();
+    let res = reflink(&src_file_path, &dest_file_path);
+    println!("{:?}", res);
+    match support {
+        ReflinkSupport::Supported => assert!(res.is_ok()),
+        ReflinkSupport::NotSupported => assert!(res.is_err()),
+        ReflinkSupport::Unknown => {}
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn check_reflink_support_relative_names() {
+    let dir = tempdir().unwrap();
+    let src = dir.path().join("src.txt");
+    let dest = dir.path().join("dest.txt");
+    fs::write(&src, b"this is a test").unwrap();
+    fs::write(&dest, b"old content").unwrap();
+
+    // Reach the temporary directory relative to the current one, without writing to the latter
+    let cwd = std::env::current_dir().unwrap();
+    let mut relative_dir = std::path::PathBuf::new();
+    for _ in cwd.components().skip(1) {
+        relative_dir.push("..");
+    }
+    relative_dir.push(dir.path().strip_prefix("/").unwrap());
+    let relative_src = relative_dir.join("src.txt");
+    let relative_dest = relative_dir.join("dest.txt");
+    assert!(relative_src.is_relative() && relative_src.exists());
+
+    // Bare file names are relative to the current directory, which has no name of its own
+    let missing_name = Path::new("reflink-copy-missing-dest.txt");
+
+    let support = check_reflink_support(&src, &dest).unwrap();
+    println!("{:?}", support);
+    assert_eq!(
+        check_reflink_support(&relative_src, &relative_dest).unwrap(),
+        support
+    );
+    assert_eq!(
+        check_reflink_support(&relative_src, missing_name).unwrap(),
+        check_reflink_support(&src, cwd.join(missing_name)).unwrap()
+    );
+    assert!(!missing_name.exists());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn check_reflink_support_missing_source() {
+    let dir = tempdir().unwrap();
+
+    let err = check_reflink_support(dir.path().join("missing.txt"), dir.path().join("dest.txt"))
+        .unwrap_err();
+    println!("{:?}", err);
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn reflink_capability_cache_ok() {
+    let tmpdir = tempdir().unwrap();
+    let input = tmpdir.path().join("in.txt");
+
+    fs::write(&input, b"hello").unwrap();
+
+    let cache = ReflinkCapabilityCache::new();
+    for i in 0..3 {
+        let out = tmpdir.path().join(format!("out{}.txt", i));
+        let res = cache.reflink_or_copy(&input, &out).unwrap();
+        println!("{:?}", res);
+        assert_eq!(fs::read(&out).unwrap(), b"hello");
+    }
+
+    let support = cache.reflink_support(&input, tmpdir.path().join("out.txt"));
+    println!("{:?}", support);
+    // do not panic for now, CI envs are old and will probably error out
+    if support.unwrap() == ReflinkSupport::NotSupported {
+        cache.invalidate(&input).unwrap();
+        assert_eq!(
+            cache
+                .reflink_support(&input, tmpdir.path().join("out.txt"))
+                .unwrap(),
+            ReflinkSupport::Unknown
+        );
+    }
+}
@@ -107,7 +107,7 @@ fn test_reflink_on_supported_config() -> std::io::Result<()> {
     let from = make_subfolder(&refs1_dir(), line!())?;
     let to = make_subfolder(&refs1_dir(), line!())?;
     create_test_file(&from.join(FILENAME))?;
-    reflink(from.join(FILENAME), to.join(FILENAME))
+    Ok(reflink(from.join(FILENAME), to.join(FILENAME))?)
 }
 
 #[test]